@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use crate::Thumbnail;
+use crate::config::{self, Overrides};
+
+/// Size and lifetime of cached thumbnails.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// Bytes of thumbnails kept in memory, `0` turns the cache off
+    pub memory_bytes: u64,
+    #[serde(rename = "ttl_secs", deserialize_with = "config::secs")]
+    pub ttl: Duration,
+    /// Directory of the on-disk tier, which is off without one
+    pub disk_dir: Option<PathBuf>,
+    /// Bytes of sources and thumbnails kept on disk
+    pub disk_bytes: u64,
+    /// Lifetime of files on disk, long enough for them to outlive restarts
+    #[serde(rename = "disk_ttl_secs", deserialize_with = "config::secs")]
+    pub disk_ttl: Duration
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            memory_bytes: 64 * 1024 * 1024,
+            ttl: Duration::from_secs(3600),
+            disk_dir: None,
+            disk_bytes: 1024 * 1024 * 1024,
+            disk_ttl: Duration::from_secs(7 * 24 * 3600)
+        }
+    }
+}
+
+impl CacheSettings {
+    /// Applies `THUMBNAILER_CACHE_MEMORY_BYTES`, `THUMBNAILER_CACHE_TTL_SECS`, `THUMBNAILER_CACHE_DISK_DIR`,
+    /// `THUMBNAILER_CACHE_DISK_BYTES` and `THUMBNAILER_CACHE_DISK_TTL_SECS`.
+    pub fn apply(&mut self, vars: &Overrides) -> Result<(), String> {
+        if let Some(val) = vars.parse("THUMBNAILER_CACHE_MEMORY_BYTES")? {
+            self.memory_bytes = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_CACHE_TTL_SECS")? {
+            self.ttl = Duration::from_secs(val);
+        }
+
+        if let Some(val) = vars.get("THUMBNAILER_CACHE_DISK_DIR") {
+            self.disk_dir = Some(PathBuf::from(val)).filter(|dir| !dir.as_os_str().is_empty());
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_CACHE_DISK_BYTES")? {
+            self.disk_bytes = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_CACHE_DISK_TTL_SECS")? {
+            self.disk_ttl = Duration::from_secs(val);
+        }
+
+        Ok(())
+    }
+}
+
+/// Keys ordered by last use, together with the total size of what they stand for.
+pub struct Lru<V> {
+    entries: HashMap<String, (V, u64, u64)>,
+    /// Keys by last use, the first one is evicted next
+    recent: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: u64
+}
+
+impl<V> Lru<V> {
+    pub fn new() -> Lru<V> {
+        Lru {
+            entries: HashMap::new(),
+            recent: BTreeMap::new(),
+            clock: 0,
+            bytes: 0
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn peek(&self, key: &str) -> Option<&V> {
+        self.entries.get(key).map(|(value, _, _)| value)
+    }
+
+    /// Looks `key` up and marks it as the most recently used.
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        let (value, _, used) = self.entries.get_mut(key)?;
+        self.clock += 1;
+        let key = self.recent.remove(used).unwrap();
+        *used = self.clock;
+        self.recent.insert(self.clock, key);
+        Some(value)
+    }
+
+    /// Adds `value` taking up `size` bytes as the most recently used entry.
+    pub fn insert(&mut self, key: String, value: V, size: u64) {
+        self.remove(&key);
+        self.clock += 1;
+        self.recent.insert(self.clock, key.clone());
+        self.entries.insert(key, (value, size, self.clock));
+        self.bytes += size;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let (value, size, used) = self.entries.remove(key)?;
+        self.recent.remove(&used);
+        self.bytes -= size;
+        Some(value)
+    }
+
+    /// Removes the least recently used entry.
+    pub fn pop_oldest(&mut self) -> Option<(String, V)> {
+        let key = self.recent.values().next()?.clone();
+        let value = self.remove(&key)?;
+        Some((key, value))
+    }
+}
+
+struct Entry {
+    thumb: Thumbnail,
+    expires: Instant
+}
+
+/// Hit and miss counters of a cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: u64
+}
+
+/// Rendered thumbnails by `ThumbOptions::cache_key`, evicting the least recently used ones
+/// once they take more than `memory_bytes`.
+pub struct MemoryCache {
+    entries: Mutex<Lru<Entry>>,
+    max_bytes: u64,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+impl MemoryCache {
+    pub fn new(settings: &CacheSettings) -> MemoryCache {
+        MemoryCache {
+            entries: Mutex::new(Lru::new()),
+            max_bytes: settings.memory_bytes,
+            ttl: settings.ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Thumbnail> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let fresh = match entries.peek(key) {
+            Some(entry) => entry.expires > Instant::now(),
+            None => false
+        };
+        if !fresh {
+            entries.remove(key);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        entries.get(key).map(|entry| entry.thumb.clone())
+    }
+
+    pub fn insert(&self, key: String, thumb: Thumbnail) {
+        let size = thumb.bytes.len() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        while entries.bytes() + size > self.max_bytes && entries.pop_oldest().is_some() {}
+
+        let expires = Instant::now() + self.ttl;
+        entries.insert(key, Entry { thumb, expires }, size);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.len(),
+            bytes: entries.bytes()
+        }
+    }
+}
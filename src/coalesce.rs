@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use crate::error::ThumbError;
+
+type Outcome<T> = Result<T, Arc<ThumbError>>;
+type Flight<T> = Shared<BoxFuture<'static, Outcome<T>>>;
+type Inflight<T> = Arc<Mutex<HashMap<String, Flight<T>>>>;
+
+/// Runs at most one future per key at a time, concurrent callers with the same key share its result.
+///
+/// Every flight runs as its own task, so it finishes and leaves the map even when all callers
+/// went away or it panicked, and one client going away never fails the others.
+pub struct SingleFlight<T> {
+    inflight: Inflight<T>
+}
+
+/// Takes a flight out of the map when its task ends, however it ends.
+struct Landing<T> {
+    inflight: Inflight<T>,
+    key: String
+}
+
+impl<T> Drop for Landing<T> {
+    fn drop(&mut self) {
+        let mut inflight = self.inflight.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        inflight.remove(&self.key);
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    pub fn new() -> SingleFlight<T> {
+        SingleFlight {
+            inflight: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    /// Joins the flight for `key`, starting it with `future` when there is none.
+    ///
+    /// A panicking flight fails its callers with `ThumbError::Panicked`.
+    pub fn run<F>(&self, key: String, future: F) -> Flight<T>
+    where
+        F: Future<Output = Outcome<T>> + Send + 'static
+    {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(flight) = inflight.get(&key) {
+            return flight.clone();
+        }
+
+        let landing = Landing {
+            inflight: self.inflight.clone(),
+            key: key.clone()
+        };
+        let task = tokio::spawn(async move {
+            let _landing = landing;
+            future.await
+        });
+        let flight = task
+            .map(|joined| joined.unwrap_or_else(|_| Err(Arc::new(ThumbError::Panicked))))
+            .boxed()
+            .shared();
+
+        inflight.insert(key, flight.clone());
+        flight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use tokio::time::{delay_for, timeout};
+    use crate::pool::{PoolSettings, RenderPool};
+
+    /// Waits until every flight has left the map.
+    async fn landed<T>(flights: &SingleFlight<T>) {
+        let wait = async {
+            while !flights.inflight.lock().unwrap().is_empty() {
+                delay_for(Duration::from_millis(1)).await;
+            }
+        };
+        timeout(Duration::from_secs(10), wait).await.expect("flight never landed");
+    }
+
+    #[tokio::test]
+    async fn abandoned_flight_lands_and_frees_the_pool() {
+        let pool = RenderPool::new(&PoolSettings { workers: 1, queue: 1 });
+        let flights: SingleFlight<bool> = SingleFlight::new();
+
+        // Keep the only worker busy until we say so
+        let (held_tx, held_rx) = oneshot::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let busy = pool.clone();
+        let blocker = tokio::spawn(async move {
+            busy.run(move || {
+                held_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                Ok(())
+            }).await
+        });
+        held_rx.await.unwrap();
+
+        // The flight takes the queue slot and waits for the worker, then every caller gives up
+        let (queued_tx, queued_rx) = oneshot::channel();
+        let queued = pool.clone();
+        let flight = flights.run(String::from("key"), async move {
+            queued_tx.send(()).unwrap();
+            queued.run(|| Ok(true)).await.map_err(Arc::new)
+        });
+        queued_rx.await.unwrap();
+        drop(flight);
+
+        release_tx.send(()).unwrap();
+        assert!(blocker.await.unwrap().is_ok());
+        landed(&flights).await;
+
+        // Both the worker and the queue slot are free again
+        let (first, second) = futures::join!(pool.run(|| Ok(())), pool.run(|| Ok(())));
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+    }
+
+    #[tokio::test]
+    async fn panicked_flight_fails_its_callers_and_lands() {
+        let flights: SingleFlight<u32> = SingleFlight::new();
+
+        let result = flights.run(String::from("key"), async { panic!("render state broke") }).await;
+        assert!(matches!(result.as_ref().map_err(|err| &**err), Err(ThumbError::Panicked)));
+        landed(&flights).await;
+
+        let result = flights.run(String::from("key"), async { Ok(1) }).await;
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_run() {
+        let flights: SingleFlight<u32> = SingleFlight::new();
+        let runs = Arc::new(Mutex::new(0));
+
+        let (start_tx, start_rx) = oneshot::channel::<()>();
+        let counted = runs.clone();
+        let first = flights.run(String::from("key"), async move {
+            start_rx.await.unwrap();
+            let mut runs = counted.lock().unwrap();
+            *runs += 1;
+            Ok(*runs)
+        });
+        let second = flights.run(String::from("key"), async { Ok(100) });
+        start_tx.send(()).unwrap();
+
+        let (first, second) = futures::join!(first, second);
+        assert_eq!(first.unwrap(), 1);
+        assert_eq!(second.unwrap(), 1);
+        assert_eq!(*runs.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Deserializer};
+use crate::cache::CacheSettings;
+use crate::fetch::{FetchSettings, SourcePolicy};
+use crate::options::Limits;
+use crate::pool::PoolSettings;
+use crate::signature::Signer;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Service settings.
+///
+/// Built from the defaults, then the TOML file given by `--config` or `THUMBNAILER_CONFIG`,
+/// then `THUMBNAILER_*` environment variables and finally command line flags, where
+/// `--max-width 2048` is the flag for `THUMBNAILER_MAX_WIDTH`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Key for signed URLs, signing is off without it
+    pub signing_key: Option<String>,
+    pub limits: Limits,
+    pub fetch: FetchSettings,
+    pub source: SourcePolicy,
+    pub render: PoolSettings,
+    pub cache: CacheSettings
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3030,
+            signing_key: None,
+            limits: Limits::default(),
+            fetch: FetchSettings::default(),
+            source: SourcePolicy::default(),
+            render: PoolSettings::default(),
+            cache: CacheSettings::default()
+        }
+    }
+}
+
+/// `THUMBNAILER_*` values from the environment and the command line, keyed by variable name.
+pub struct Overrides {
+    vars: HashMap<String, String>,
+    flags: Vec<String>,
+    used: RefCell<HashSet<String>>
+}
+
+impl Overrides {
+    /// Collects the environment, then `args` on top of it. Returns the arguments that are not flags too.
+    fn new(mut args: impl Iterator<Item = String>) -> Result<(Overrides, Vec<String>), String> {
+        let mut vars: HashMap<String, String> = std::env::vars()
+            .filter(|(name, _)| name.starts_with("THUMBNAILER_"))
+            .collect();
+        let mut flags = vec![];
+        let mut positional = vec![];
+
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => {
+                    positional.push(arg);
+                    continue;
+                }
+            };
+
+            let (flag, value) = match flag.find('=') {
+                Some(index) => (&flag[..index], flag[index + 1..].to_string()),
+                None => match args.next() {
+                    Some(value) => (flag, value),
+                    None => return Err(format!("--{} needs a value", flag))
+                }
+            };
+
+            let name = format!("THUMBNAILER_{}", flag.to_ascii_uppercase().replace('-', "_"));
+            vars.insert(name.clone(), value);
+            flags.push(name);
+        }
+
+        Ok((Overrides { vars, flags, used: RefCell::new(HashSet::new()) }, positional))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.used.borrow_mut().insert(name.to_string());
+        self.vars.get(name).map(String::as_str)
+    }
+
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.get(name) {
+            Some(val) => val.parse().map(Some).map_err(|_| format!("{} has an invalid value: {}", name, val)),
+            None => Ok(None)
+        }
+    }
+
+    /// Fails on command line flags no setting asked for.
+    fn check_flags(&self) -> Result<(), String> {
+        let used = self.used.borrow();
+        match self.flags.iter().find(|name| !used.contains(*name)) {
+            Some(name) => {
+                let flag = name["THUMBNAILER_".len()..].to_ascii_lowercase().replace('_', "-");
+                Err(format!("Unknown flag --{}", flag))
+            },
+            None => Ok(())
+        }
+    }
+}
+
+/// Reads a duration given in milliseconds.
+pub fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+/// Reads a duration given in seconds.
+pub fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+impl Config {
+    /// Loads and validates the configuration for the command line `args` (without the program name).
+    ///
+    /// Returns the arguments left over after the flags, i.e. the subcommand.
+    pub fn load(args: impl Iterator<Item = String>) -> Result<(Config, Vec<String>), BoxError> {
+        let (vars, positional) = Overrides::new(args)?;
+
+        let mut config = match vars.get("THUMBNAILER_CONFIG") {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|err| format!("Failed reading config {}: {}", path, err))?;
+                toml::from_str(&contents)
+                    .map_err(|err| format!("Invalid config {}: {}", path, err))?
+            },
+            None => Config::default()
+        };
+
+        config.apply(&vars)?;
+        vars.check_flags()?;
+
+        for pattern in config.source.allowed_hosts.iter_mut().chain(config.source.allowed_schemes.iter_mut()) {
+            pattern.make_ascii_lowercase();
+        }
+
+        config.validate()?;
+
+        Ok((config, positional))
+    }
+
+    fn apply(&mut self, vars: &Overrides) -> Result<(), String> {
+        if let Some(val) = vars.parse("THUMBNAILER_BIND")? {
+            self.bind = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_PORT")? {
+            self.port = val;
+        }
+
+        if let Some(val) = vars.get("THUMBNAILER_SIGNING_KEY") {
+            self.signing_key = Some(val.to_string());
+        }
+
+        self.limits.apply(vars)?;
+        self.fetch.apply(vars)?;
+        self.source.apply(vars)?;
+        self.render.apply(vars)?;
+        self.cache.apply(vars)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let limits = &self.limits;
+        if limits.max_width == 0 || limits.max_height == 0 {
+            return Err(String::from("limits.max_width and limits.max_height must be positive"));
+        }
+
+        if limits.default_width == 0 || limits.default_width > limits.max_width {
+            return Err(format!("limits.default_width must be between 1 and {}", limits.max_width));
+        }
+
+        if limits.max_download_bytes == 0
+            || limits.max_source_width == 0
+            || limits.max_source_height == 0
+            || limits.max_source_pixels == 0 {
+            return Err(String::from("limits.max_download_bytes and limits.max_source_* must be positive"));
+        }
+
+        if self.fetch.connect_timeout == Duration::from_millis(0) || self.fetch.read_timeout == Duration::from_millis(0) {
+            return Err(String::from("fetch.connect_timeout_ms and fetch.read_timeout_ms must be positive"));
+        }
+
+        let schemes = &self.source.allowed_schemes;
+        if schemes.is_empty() || schemes.iter().any(|scheme| scheme != "http" && scheme != "https") {
+            return Err(String::from("source.allowed_schemes must be a non-empty list of http and https"));
+        }
+
+        if self.render.workers == 0 {
+            return Err(String::from("render.workers must be positive"));
+        }
+
+        if self.cache.disk_dir.is_some() && self.cache.disk_bytes == 0 {
+            return Err(String::from("cache.disk_bytes must be positive when cache.disk_dir is set"));
+        }
+
+        Ok(())
+    }
+
+    /// Signer for `signing_key`, `None` when signing is not enabled.
+    pub fn signer(&self) -> Option<Signer> {
+        self.signing_key.as_ref()
+            .filter(|key| !key.is_empty())
+            .map(|key| Signer::new(key.as_bytes()))
+    }
+}
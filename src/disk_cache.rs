@@ -0,0 +1,277 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use sha2::{Digest, Sha256};
+use crate::cache::{CacheSettings, CacheStats, Lru};
+
+/// Filesystem tier below `MemoryCache`, keeping sources and thumbnails across restarts.
+///
+/// Entries live in `{dir}/{ab}/{cd}/{sha256 of the key}` and start with a line of metadata
+/// (content type or output format) followed by the data. When each file was last used is only
+/// tracked in memory, after a restart the least recently written files are evicted first.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    /// Write time of every file, by file name
+    files: Mutex<Lru<SystemTime>>,
+    /// Makes temporary file names unique while entries are written
+    writes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+fn file_name(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn is_hex(name: &str, len: usize) -> bool {
+    name.len() == len && name.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Subdirectories of `dir` named by `len` lowercase hex digits. Anything else, and directories
+/// we cannot read, is skipped.
+fn shards(dir: &Path, len: usize) -> Vec<(String, PathBuf)> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![]
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false))
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if is_hex(&name, len) { Some((name, entry.path())) } else { None }
+        })
+        .collect()
+}
+
+/// Every cache file below `dir` with its size and modification time, removing leftover temporary files.
+///
+/// Only `{ab}/{cd}/{abcd...}` entries with a 64 digit name are indexed, anything else in the
+/// directory is left alone.
+fn scan(dir: &Path) -> io::Result<Vec<(String, u64, SystemTime)>> {
+    // Fail early when the cache directory itself is unusable
+    std::fs::read_dir(dir)?;
+
+    let mut files = vec![];
+    for (shard, shard_path) in shards(dir, 2) {
+        for (subshard, subshard_path) in shards(&shard_path, 2) {
+            let entries = match std::fs::read_dir(&subshard_path) {
+                Ok(entries) => entries,
+                Err(_) => continue
+            };
+
+            for file in entries.filter_map(|entry| entry.ok()) {
+                let name = match file.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue
+                };
+
+                if name.ends_with(".tmp") && matches!(name.get(..64), Some(hash) if is_hex(hash, 64)) {
+                    let _ = std::fs::remove_file(file.path());
+                    continue;
+                }
+
+                if !is_hex(&name, 64) || name[..2] != shard || name[2..4] != subshard {
+                    continue;
+                }
+
+                match file.metadata() {
+                    Ok(metadata) if metadata.is_file() => {
+                        if let Ok(modified) = metadata.modified() {
+                            files.push((name, metadata.len(), modified));
+                        }
+                    },
+                    _ => continue
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+impl DiskCache {
+    /// Opens the directory of `settings.disk_dir` and indexes the files already in it,
+    /// `None` when the disk tier is off.
+    pub fn open(settings: &CacheSettings) -> io::Result<Option<DiskCache>> {
+        let dir = match &settings.disk_dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(None)
+        };
+        std::fs::create_dir_all(&dir)?;
+
+        let mut found = scan(&dir)?;
+        found.sort_by_key(|&(_, _, modified)| modified);
+
+        let cache = DiskCache {
+            dir,
+            max_bytes: settings.disk_bytes,
+            ttl: settings.disk_ttl,
+            files: Mutex::new(Lru::new()),
+            writes: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        };
+
+        let evicted = {
+            let mut files = cache.files.lock().unwrap();
+            for (name, size, modified) in found {
+                files.insert(name, modified, size);
+            }
+            cache.evict(&mut files)
+        };
+        for name in evicted {
+            let _ = std::fs::remove_file(cache.path(&name));
+        }
+
+        Ok(Some(cache))
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(&name[..2]).join(&name[2..4]).join(name)
+    }
+
+    /// Drops the least recently used files from the index until they fit, returning their names.
+    fn evict(&self, files: &mut Lru<SystemTime>) -> Vec<String> {
+        let mut evicted = vec![];
+        while files.bytes() > self.max_bytes {
+            match files.pop_oldest() {
+                Some((name, _)) => evicted.push(name),
+                None => break
+            }
+        }
+        evicted
+    }
+
+    /// Metadata and data stored for `key`, if there is a fresh entry.
+    pub async fn get(&self, key: &str) -> Option<(String, Vec<u8>)> {
+        let name = file_name(key);
+        let written = self.files.lock().unwrap().get(&name).copied();
+
+        let fresh = match written {
+            Some(written) => written.elapsed().map(|age| age < self.ttl).unwrap_or(false),
+            None => false
+        };
+
+        let contents = if fresh {
+            tokio::fs::read(self.path(&name)).await.ok()
+        } else {
+            None
+        };
+
+        let entry = contents.and_then(|mut contents| {
+            let end = contents.iter().position(|&byte| byte == b'\n')?;
+            let data = contents.split_off(end + 1);
+            let meta = String::from_utf8(contents[..end].to_vec()).ok()?;
+            Some((meta, data))
+        });
+
+        match entry {
+            Some(entry) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry)
+            },
+            None => {
+                if written.is_some() {
+                    self.files.lock().unwrap().remove(&name);
+                    let _ = tokio::fs::remove_file(self.path(&name)).await;
+                }
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Stores `data` with its `meta` line under `key`. Failures are only logged, the cache is best effort.
+    pub async fn insert(&self, key: &str, meta: &str, data: &[u8]) {
+        let size = (meta.len() + 1 + data.len()) as u64;
+        if size > self.max_bytes {
+            return;
+        }
+
+        let name = file_name(key);
+        let path = self.path(&name);
+        let mut contents = Vec::with_capacity(size as usize);
+        contents.extend_from_slice(meta.as_bytes());
+        contents.push(b'\n');
+        contents.extend_from_slice(data);
+
+        // Written next to the entry and renamed, so readers never see a partial file
+        let temp = path.with_extension(format!("{}.tmp", self.writes.fetch_add(1, Ordering::Relaxed)));
+        let written = async {
+            tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+            tokio::fs::write(&temp, &contents).await?;
+            tokio::fs::rename(&temp, &path).await
+        };
+        if let Err(err) = written.await {
+            eprintln!("Failed writing cache file {}: {}", path.display(), err);
+            let _ = tokio::fs::remove_file(&temp).await;
+            return;
+        }
+
+        let evicted = {
+            let mut files = self.files.lock().unwrap();
+            files.insert(name, SystemTime::now(), size);
+            self.evict(&mut files)
+        };
+        for name in evicted {
+            let _ = tokio::fs::remove_file(self.path(&name)).await;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let files = self.files.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: files.len(),
+            bytes: files.bytes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("thumbnailer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn scan_only_indexes_cache_entries() {
+        let dir = temp_dir("scan");
+        let name = file_name("thumb:key");
+        let shard = dir.join(&name[..2]).join(&name[2..4]);
+        std::fs::create_dir_all(&shard).unwrap();
+        std::fs::write(shard.join(&name), b"png\ndata").unwrap();
+        let temp = shard.join(format!("{}.3.tmp", name));
+        std::fs::write(&temp, b"partial").unwrap();
+
+        // Things a cache directory on a shared or freshly formatted volume may contain
+        std::fs::create_dir_all(dir.join("lost+found")).unwrap();
+        std::fs::write(dir.join("README"), b"").unwrap();
+        std::fs::create_dir_all(dir.join("a").join("b")).unwrap();
+        std::fs::write(dir.join("a").join("b").join("c"), b"").unwrap();
+        std::fs::write(shard.join("abc"), b"").unwrap();
+        std::fs::write(shard.join(file_name("other")), b"").unwrap();
+        std::fs::create_dir_all(dir.join("zz").join("zz")).unwrap();
+        std::fs::write(dir.join("zz").join("zz").join("x".repeat(64)), b"").unwrap();
+
+        let found = scan(&dir).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, name);
+        assert_eq!(found[0].1, 8);
+        assert!(!temp.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
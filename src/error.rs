@@ -0,0 +1,211 @@
+use std::error::Error;
+use std::fmt;
+use hyper::{Body, Response, StatusCode};
+use crate::signature::SignatureError;
+
+#[derive(Debug)]
+pub enum ParamError {
+    Missing { param: &'static str },
+    Invalid { param: &'static str, value: String },
+    OutOfRange { param: &'static str, value: String, min: u32, max: u32 },
+    OutOfBounds { param: &'static str, value: String, width: u32, height: u32 }
+}
+
+impl ParamError {
+    /// Name of the offending query parameter.
+    pub fn param(&self) -> &'static str {
+        match self {
+            ParamError::Missing { param }
+            | ParamError::Invalid { param, .. }
+            | ParamError::OutOfRange { param, .. }
+            | ParamError::OutOfBounds { param, .. } => param
+        }
+    }
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamError::Missing { param } => write!(f, "Missing required parameter {}", param),
+            ParamError::Invalid { param, value } => write!(f, "Invalid value for {}: {}", param, value),
+            ParamError::OutOfRange { param, value, min, max } => {
+                write!(f, "{} must be between {} and {}, got {}", param, min, max, value)
+            },
+            ParamError::OutOfBounds { param, value, width, height } => {
+                write!(f, "{} {} does not fit the {}x{} image", param, value, width, height)
+            }
+        }
+    }
+}
+
+impl Error for ParamError {}
+
+#[derive(Debug)]
+pub enum UnsupportedFormat {
+    Input(Option<String>)
+}
+
+impl fmt::Display for UnsupportedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnsupportedFormat::Input(Some(content_type)) => write!(f, "Unsupported input format: {}", content_type),
+            UnsupportedFormat::Input(None) => write!(f, "Unsupported input format")
+        }
+    }
+}
+
+impl Error for UnsupportedFormat {}
+
+/// Everything that can go wrong while serving a thumbnail.
+#[derive(Debug)]
+pub enum ThumbError {
+    /// The request options did not validate
+    BadParams(ParamError),
+    /// Signing is enabled and the request signature is missing, wrong or expired
+    Forbidden(SignatureError),
+    /// The source URL points somewhere the source policy does not allow
+    SourceNotAllowed(String),
+    /// The source could not be downloaded at all
+    Fetch(hyper::Error),
+    /// The source server did not answer in time, even after retrying
+    Timeout,
+    /// The source kept redirecting
+    TooManyRedirects,
+    /// The source body is larger than the download limit, in bytes
+    TooLarge(u64),
+    /// The source dimensions exceed the decode limits
+    TooManyPixels(u32, u32),
+    /// The thumbnail, or the image resized on the way to it, would exceed the size limits
+    ThumbnailTooLarge(u32, u32),
+    /// The source server answered with a non-2xx status
+    Upstream(StatusCode),
+    /// We have no decoder or encoder for the format
+    UnsupportedFormat(UnsupportedFormat),
+    /// The source could not be decoded
+    Decode(image::ImageError),
+    /// The thumbnail could not be encoded
+    Encode(image::ImageError),
+    /// Every render worker is busy and the queue is full
+    Overloaded,
+    /// The image work panicked
+    Panicked
+}
+
+impl ThumbError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ThumbError::BadParams(_) => StatusCode::BAD_REQUEST,
+            ThumbError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ThumbError::SourceNotAllowed(_) => StatusCode::FORBIDDEN,
+            ThumbError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ThumbError::Fetch(_) | ThumbError::TooManyRedirects => StatusCode::BAD_GATEWAY,
+            ThumbError::TooLarge(_)
+            | ThumbError::TooManyPixels(..)
+            | ThumbError::ThumbnailTooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            ThumbError::Upstream(StatusCode::NOT_FOUND) => StatusCode::NOT_FOUND,
+            ThumbError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ThumbError::UnsupportedFormat(_) | ThumbError::Decode(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ThumbError::Encode(_) | ThumbError::Panicked => StatusCode::INTERNAL_SERVER_ERROR,
+            ThumbError::Overloaded => StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+
+    /// Builds the error response with a JSON body like `{"status": 400, "error": "..."}`.
+    ///
+    /// Parameter errors also carry the name of the offending parameter as `param`,
+    /// `503` answers a `Retry-After` header.
+    pub fn to_response(&self) -> Response<Body> {
+        let status = self.status();
+        let mut body = serde_json::json!({
+            "status": status.as_u16(),
+            "error": self.to_string()
+        });
+
+        if let ThumbError::BadParams(err) = self {
+            body["param"] = serde_json::Value::from(err.param());
+        }
+
+        let mut response = Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/json");
+
+        if let ThumbError::Overloaded = self {
+            response = response.header(hyper::header::RETRY_AFTER, "1");
+        }
+
+        response
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+impl fmt::Display for ThumbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThumbError::BadParams(err) => write!(f, "{}", err),
+            ThumbError::Forbidden(err) => write!(f, "{}", err),
+            ThumbError::SourceNotAllowed(reason) => write!(f, "Source not allowed: {}", reason),
+            ThumbError::Fetch(err) => write!(f, "Failed fetching source image: {}", err),
+            ThumbError::Timeout => write!(f, "Timed out fetching source image"),
+            ThumbError::TooManyRedirects => write!(f, "Source image redirected too many times"),
+            ThumbError::TooLarge(limit) => write!(f, "Source image is larger than {} bytes", limit),
+            ThumbError::TooManyPixels(width, height) => {
+                write!(f, "Source image is {}x{}, which exceeds the dimension limits", width, height)
+            },
+            ThumbError::ThumbnailTooLarge(width, height) => {
+                write!(f, "Thumbnail would be {}x{}, which exceeds the size limits", width, height)
+            },
+            ThumbError::Upstream(status) => write!(f, "Source server responded with {}", status),
+            ThumbError::UnsupportedFormat(err) => write!(f, "{}", err),
+            ThumbError::Decode(err) => write!(f, "Failed decoding source image: {}", err),
+            ThumbError::Encode(err) => write!(f, "Failed encoding thumbnail: {}", err),
+            ThumbError::Overloaded => write!(f, "Too many thumbnails in progress, try again later"),
+            ThumbError::Panicked => write!(f, "Failed rendering thumbnail")
+        }
+    }
+}
+
+impl Error for ThumbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ThumbError::BadParams(err) => Some(err),
+            ThumbError::Forbidden(err) => Some(err),
+            ThumbError::Fetch(err) => Some(err),
+            ThumbError::SourceNotAllowed(_)
+            | ThumbError::Timeout
+            | ThumbError::TooManyRedirects
+            | ThumbError::TooLarge(_)
+            | ThumbError::TooManyPixels(..)
+            | ThumbError::ThumbnailTooLarge(..)
+            | ThumbError::Upstream(_)
+            | ThumbError::Overloaded
+            | ThumbError::Panicked => None,
+            ThumbError::UnsupportedFormat(err) => Some(err),
+            ThumbError::Decode(err) | ThumbError::Encode(err) => Some(err)
+        }
+    }
+}
+
+impl From<ParamError> for ThumbError {
+    fn from(err: ParamError) -> Self {
+        ThumbError::BadParams(err)
+    }
+}
+
+impl From<SignatureError> for ThumbError {
+    fn from(err: SignatureError) -> Self {
+        ThumbError::Forbidden(err)
+    }
+}
+
+impl From<UnsupportedFormat> for ThumbError {
+    fn from(err: UnsupportedFormat) -> Self {
+        ThumbError::UnsupportedFormat(err)
+    }
+}
+
+impl From<hyper::Error> for ThumbError {
+    fn from(err: hyper::Error) -> Self {
+        ThumbError::Fetch(err)
+    }
+}
@@ -0,0 +1,492 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::future::{BoxFuture, FutureExt};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::client::connect::dns::Name;
+use hyper::header::LOCATION;
+use hyper_rustls::HttpsConnector;
+use serde::Deserialize;
+use tokio::time::{delay_for, timeout};
+use tower_service::Service;
+use url::{Host, Url};
+use crate::config::{self, Overrides};
+use crate::error::ThumbError;
+
+/// HTTP(S) client for sources, connecting only to addresses the source policy allows.
+pub type Client = hyper::Client<HttpsConnector<HttpConnector<PinnedResolver>>>;
+
+/// Upstream statuses worth another attempt.
+const RETRY_STATUSES: [StatusCode; 3] = [
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT
+];
+
+/// Timeouts, redirect and retry limits for downloading sources.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FetchSettings {
+    #[serde(rename = "connect_timeout_ms", deserialize_with = "config::millis")]
+    pub connect_timeout: Duration,
+    /// Longest wait for the response headers and for every body chunk
+    #[serde(rename = "read_timeout_ms", deserialize_with = "config::millis")]
+    pub read_timeout: Duration,
+    pub max_redirects: usize,
+    /// Attempts after the first one for failed requests and 502/503/504 answers
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every following one
+    #[serde(rename = "retry_backoff_ms", deserialize_with = "config::millis")]
+    pub retry_backoff: Duration
+}
+
+impl Default for FetchSettings {
+    fn default() -> Self {
+        FetchSettings {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(15),
+            max_redirects: 10,
+            retries: 2,
+            retry_backoff: Duration::from_millis(200)
+        }
+    }
+}
+
+impl FetchSettings {
+    /// Applies `THUMBNAILER_CONNECT_TIMEOUT_MS`, `THUMBNAILER_READ_TIMEOUT_MS`, `THUMBNAILER_MAX_REDIRECTS`,
+    /// `THUMBNAILER_FETCH_RETRIES` and `THUMBNAILER_RETRY_BACKOFF_MS`.
+    pub fn apply(&mut self, vars: &Overrides) -> Result<(), String> {
+        if let Some(val) = vars.parse("THUMBNAILER_CONNECT_TIMEOUT_MS")? {
+            self.connect_timeout = Duration::from_millis(val);
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_READ_TIMEOUT_MS")? {
+            self.read_timeout = Duration::from_millis(val);
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_MAX_REDIRECTS")? {
+            self.max_redirects = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_FETCH_RETRIES")? {
+            self.retries = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_RETRY_BACKOFF_MS")? {
+            self.retry_backoff = Duration::from_millis(val);
+        }
+
+        Ok(())
+    }
+
+    /// Client for fetching sources. It does not follow redirects, `fetch` does so every hop is checked against `policy`.
+    pub fn client(&self, policy: &SourcePolicy) -> Client {
+        let mut http = HttpConnector::new_with_resolver(PinnedResolver { allow_private: policy.allow_private });
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(self.connect_timeout));
+
+        let mut tls = rustls::ClientConfig::new();
+        tls.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+        hyper::Client::builder().build(HttpsConnector::from((http, tls)))
+    }
+}
+
+/// Which sources the service may fetch from.
+///
+/// The client does not follow redirects itself, `fetch` checks every hop against the policy.
+/// Host names are checked by `PinnedResolver` while connecting, so the addresses we check are
+/// the ones we connect to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcePolicy {
+    /// Host patterns, either exact (`example.com`) or subdomain wildcards (`*.example.com`); empty allows any host
+    pub allowed_hosts: Vec<String>,
+    pub allowed_schemes: Vec<String>,
+    /// Allow loopback, private, link-local and other non-public addresses
+    pub allow_private: bool
+}
+
+impl Default for SourcePolicy {
+    fn default() -> Self {
+        SourcePolicy {
+            allowed_hosts: vec![],
+            allowed_schemes: vec![String::from("http"), String::from("https")],
+            allow_private: false
+        }
+    }
+}
+
+fn split_list(val: &str) -> Vec<String> {
+    val.split(',')
+        .map(|item| item.trim().to_ascii_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl SourcePolicy {
+    /// Applies `THUMBNAILER_ALLOWED_HOSTS`, `THUMBNAILER_ALLOWED_SCHEMES` (comma separated lists)
+    /// and `THUMBNAILER_ALLOW_PRIVATE_ADDRESSES`.
+    pub fn apply(&mut self, vars: &Overrides) -> Result<(), String> {
+        if let Some(val) = vars.get("THUMBNAILER_ALLOWED_HOSTS") {
+            self.allowed_hosts = split_list(val);
+        }
+
+        if let Some(val) = vars.get("THUMBNAILER_ALLOWED_SCHEMES") {
+            self.allowed_schemes = split_list(val);
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_ALLOW_PRIVATE_ADDRESSES")? {
+            self.allow_private = val;
+        }
+
+        Ok(())
+    }
+
+    fn host_allowed(&self, host: &str) -> bool {
+        if self.allowed_hosts.is_empty() {
+            return true;
+        }
+
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'),
+            None => *pattern == host
+        })
+    }
+
+    /// Checks scheme and host of `url`, and the address when the host is an IP literal.
+    pub(crate) fn check(&self, url: &Url) -> Result<(), ThumbError> {
+        if !self.allowed_schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(ThumbError::SourceNotAllowed(format!("scheme {} is not allowed", url.scheme())));
+        }
+
+        let host = match url.host() {
+            Some(host) => host,
+            None => return Err(ThumbError::SourceNotAllowed(String::from("source has no host")))
+        };
+
+        let (host_name, ip) = match host {
+            Host::Domain(domain) => (domain.to_string(), None),
+            Host::Ipv4(ip) => (ip.to_string(), Some(IpAddr::V4(ip))),
+            Host::Ipv6(ip) => (ip.to_string(), Some(IpAddr::V6(ip)))
+        };
+
+        if !self.host_allowed(&host_name) {
+            return Err(ThumbError::SourceNotAllowed(format!("host {} is not allowed", host_name)));
+        }
+
+        match ip {
+            Some(ip) if !self.allow_private && !is_public(&ip) => {
+                Err(ThumbError::SourceNotAllowed(format!("host {} is a non-public address", host_name)))
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+/// Returned by `PinnedResolver` when a host name resolves to an address the policy refuses.
+#[derive(Debug)]
+struct NonPublicAddress {
+    host: String,
+    ip: IpAddr
+}
+
+impl fmt::Display for NonPublicAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "host {} resolves to non-public address {}", self.host, self.ip)
+    }
+}
+
+impl Error for NonPublicAddress {}
+
+/// Resolver for the source client that refuses names pointing at any non-public address.
+///
+/// The connector only ever sees the addresses checked here, so a DNS server answering
+/// differently on a second lookup cannot sneak an internal address past the policy.
+#[derive(Debug, Clone)]
+pub struct PinnedResolver {
+    allow_private: bool
+}
+
+impl Service<Name> for PinnedResolver {
+    type Response = std::vec::IntoIter<IpAddr>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allow_private = self.allow_private;
+        async move {
+            let addresses: Vec<IpAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect();
+
+            if !allow_private {
+                if let Some(ip) = addresses.iter().find(|ip| !is_public(ip)) {
+                    return Err(NonPublicAddress { host: name.to_string(), ip: *ip }.into());
+                }
+            }
+
+            Ok(addresses.into_iter())
+        }.boxed()
+    }
+}
+
+fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // "this network", carrier-grade NAT, IETF protocol assignments, benchmarking and reserved ranges
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240)
+}
+
+fn is_public_v6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    // IPv4-mapped (::ffff:a.b.c.d) and NAT64 (64:ff9b::a.b.c.d) addresses reach the embedded IPv4 address
+    let embedded_v4 = (segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff)
+        || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+    if embedded_v4 {
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+        return is_public_v4(&Ipv4Addr::new(a, b, c, d));
+    }
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local fc00::/7, link-local fe80::/10 and documentation 2001:db8::/32
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip)
+    }
+}
+
+/// Maps a client error, pulling refused addresses and connect timeouts out of the connector's error.
+fn fetch_error(err: hyper::Error) -> ThumbError {
+    let mut source = err.source();
+    while let Some(cause) = source {
+        if let Some(refused) = cause.downcast_ref::<NonPublicAddress>() {
+            return ThumbError::SourceNotAllowed(refused.to_string());
+        }
+        if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+            if io_err.kind() == io::ErrorKind::TimedOut {
+                return ThumbError::Timeout;
+            }
+        }
+        source = cause.source();
+    }
+
+    ThumbError::Fetch(err)
+}
+
+/// Sends a GET for `url`, retrying failed requests and 502/503/504 answers with exponential backoff.
+async fn send(client: &Client, url: &Url, settings: &FetchSettings) -> Result<Response<Body>, ThumbError> {
+    let uri: Uri = url.as_str()
+        .parse()
+        .map_err(|_| ThumbError::SourceNotAllowed(format!("{} is not a valid URL", url)))?;
+
+    let mut attempt = 0;
+    loop {
+        let request = Request::get(uri.clone())
+            .body(Body::empty())
+            .expect("GET request with a valid URI");
+
+        let result = match timeout(settings.read_timeout, client.request(request)).await {
+            Ok(result) => result.map_err(fetch_error),
+            Err(_) => Err(ThumbError::Timeout)
+        };
+
+        let retry = match &result {
+            Ok(response) => RETRY_STATUSES.contains(&response.status()),
+            Err(ThumbError::SourceNotAllowed(_)) => false,
+            Err(_) => true
+        };
+        if !retry || attempt >= settings.retries {
+            return result;
+        }
+
+        delay_for(settings.retry_backoff * 2u32.pow(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Parses a source URL, which has to be absolute.
+pub fn parse_source(url: &str) -> Result<Url, ThumbError> {
+    Url::parse(url).map_err(|_| ThumbError::SourceNotAllowed(format!("{} is not a valid URL", url)))
+}
+
+/// Requests `url`, following redirects by hand so that every hop goes through `policy`.
+pub async fn fetch(client: &Client, url: &str, policy: &SourcePolicy, settings: &FetchSettings) -> Result<Response<Body>, ThumbError> {
+    let mut url = parse_source(url)?;
+
+    for _ in 0..=settings.max_redirects {
+        policy.check(&url)?;
+
+        let response = send(client, &url, settings).await?;
+
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+
+        let location = response.headers()
+            .get(LOCATION)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|location| url.join(location).ok());
+
+        url = match location {
+            Some(location) => location,
+            // A redirect without a usable Location is handed back as is and fails as a non-2xx
+            None => return Ok(response)
+        };
+    }
+
+    Err(ThumbError::TooManyRedirects)
+}
+
+/// Reads the response body chunk by chunk, giving up as soon as it grows past `limit` bytes
+/// or a chunk takes longer than the read timeout.
+pub async fn read_body(response: Response<Body>, limit: u64, settings: &FetchSettings) -> Result<Vec<u8>, ThumbError> {
+    let mut response = response.into_body();
+
+    // Refuse early when the server tells us the size, the streaming check still covers lying servers
+    let content_length = response.size_hint().exact();
+    if let Some(length) = content_length {
+        if length > limit {
+            return Err(ThumbError::TooLarge(limit));
+        }
+    }
+
+    let mut body = Vec::with_capacity(content_length.unwrap_or(0) as usize);
+    while let Some(chunk) = timeout(settings.read_timeout, response.data()).await.map_err(|_| ThumbError::Timeout)? {
+        let chunk = chunk?;
+        if body.len() as u64 + chunk.len() as u64 > limit {
+            return Err(ThumbError::TooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(ip: &str) -> bool {
+        is_public_v4(&ip.parse().unwrap())
+    }
+
+    fn v6(ip: &str) -> bool {
+        is_public_v6(&ip.parse().unwrap())
+    }
+
+    fn policy(hosts: &[&str]) -> SourcePolicy {
+        SourcePolicy {
+            allowed_hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            ..SourcePolicy::default()
+        }
+    }
+
+    #[test]
+    fn public_v4() {
+        assert!(v4("93.184.216.34"));
+        assert!(v4("8.8.8.8"));
+        assert!(v4("100.128.0.1"));
+    }
+
+    #[test]
+    fn non_public_v4() {
+        // loopback
+        assert!(!v4("127.0.0.1"));
+        assert!(!v4("127.255.0.1"));
+        // RFC 1918
+        assert!(!v4("10.1.2.3"));
+        assert!(!v4("172.16.0.1"));
+        assert!(!v4("172.31.255.255"));
+        assert!(!v4("192.168.1.1"));
+        // carrier-grade NAT
+        assert!(!v4("100.64.0.1"));
+        assert!(!v4("100.127.255.255"));
+        // link-local, including cloud metadata endpoints
+        assert!(!v4("169.254.169.254"));
+        assert!(!v4("0.0.0.0"));
+        assert!(!v4("255.255.255.255"));
+        assert!(!v4("224.0.0.1"));
+    }
+
+    #[test]
+    fn public_v6() {
+        assert!(v6("2606:2800:220:1:248:1893:25c8:1946"));
+        assert!(v6("::ffff:93.184.216.34"));
+        assert!(v6("64:ff9b::5db8:d822"));
+    }
+
+    #[test]
+    fn non_public_v6() {
+        assert!(!v6("::1"));
+        assert!(!v6("::"));
+        // IPv4-mapped
+        assert!(!v6("::ffff:127.0.0.1"));
+        assert!(!v6("::ffff:10.0.0.1"));
+        assert!(!v6("::ffff:169.254.169.254"));
+        // NAT64
+        assert!(!v6("64:ff9b::7f00:1"));
+        assert!(!v6("64:ff9b::a9fe:a9fe"));
+        // unique local
+        assert!(!v6("fc00::1"));
+        assert!(!v6("fd12:3456:789a::1"));
+        // link-local and documentation
+        assert!(!v6("fe80::1"));
+        assert!(!v6("2001:db8::1"));
+    }
+
+    #[test]
+    fn any_host_without_patterns() {
+        assert!(policy(&[]).host_allowed("example.com"));
+    }
+
+    #[test]
+    fn exact_host_pattern() {
+        let policy = policy(&["example.com"]);
+        assert!(policy.host_allowed("example.com"));
+        assert!(policy.host_allowed("EXAMPLE.com"));
+        assert!(!policy.host_allowed("images.example.com"));
+        assert!(!policy.host_allowed("example.com.evil.net"));
+    }
+
+    #[test]
+    fn wildcard_host_pattern() {
+        let policy = policy(&["*.example.com"]);
+        assert!(policy.host_allowed("images.example.com"));
+        assert!(policy.host_allowed("a.b.example.com"));
+        assert!(!policy.host_allowed("example.com"));
+        assert!(!policy.host_allowed("evilexample.com"));
+        assert!(!policy.host_allowed("example.com.evil.net"));
+    }
+}
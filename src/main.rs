@@ -1,142 +1,523 @@
 use std::convert::Infallible;
-use std::collections::HashMap;
+use futures::FutureExt;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode, Method};
-use image::{GenericImageView, ColorType, imageops::FilterType};
-use std::io::BufWriter;
-use std::borrow::Cow;
+use image::{GenericImageView, ColorType, DynamicImage, ImageFormat, RgbaImage};
+use std::io::{BufWriter, Cursor};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Instant;
 
-#[derive(Debug)]
-struct ThumbOptions {
-    url: String,
-    width: u32
+mod cache;
+mod coalesce;
+mod config;
+mod disk_cache;
+mod error;
+mod fetch;
+mod options;
+mod pool;
+mod signature;
+mod smartcrop;
+
+use cache::{CacheStats, MemoryCache};
+use coalesce::SingleFlight;
+use config::Config;
+use disk_cache::DiskCache;
+use error::{ThumbError, UnsupportedFormat};
+use fetch::{FetchSettings, SourcePolicy};
+use options::{Fit, Flip, Limits, OutputFormat, ThumbOptions, negotiate_format};
+use pool::RenderPool;
+use signature::Signer;
+
+fn format_from_mime(mime: &str) -> Option<ImageFormat> {
+    let essence = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    match essence.as_str() {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(ImageFormat::Jpeg),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        "image/bmp" | "image/x-ms-bmp" => Some(ImageFormat::Bmp),
+        "image/tiff" => Some(ImageFormat::Tiff),
+        "image/x-icon" | "image/vnd.microsoft.icon" => Some(ImageFormat::Ico),
+        "image/x-tga" | "image/x-targa" => Some(ImageFormat::Tga),
+        "image/vnd.radiance" => Some(ImageFormat::Hdr),
+        "image/x-portable-anymap" | "image/x-portable-bitmap"
+            | "image/x-portable-graymap" | "image/x-portable-pixmap" => Some(ImageFormat::Pnm),
+        _ => None
+    }
+}
+
+/// Picks the decoder for the downloaded bytes: magic bytes first, then the upstream `Content-Type`.
+fn detect_format(bytes: &[u8], content_type: Option<&str>) -> Result<ImageFormat, UnsupportedFormat> {
+    if let Ok(format) = image::guess_format(bytes) {
+        return Ok(format);
+    }
+
+    content_type
+        .and_then(format_from_mime)
+        .ok_or_else(|| UnsupportedFormat::Input(content_type.map(String::from)))
+}
+
+/// EXIF Orientation tag of the source, 1 (upright) when there is none or it cannot be read.
+fn exif_orientation(bytes: &[u8]) -> u32 {
+    let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) {
+        Ok(exif) => exif,
+        Err(_) => return 1
+    };
+
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1)
+}
+
+/// Rotates and flips the decoded image so it is upright for the given EXIF orientation.
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image
+    }
 }
 
-impl ThumbOptions {
-    fn new(opts: HashMap<String, String>) -> ThumbOptions {
-        let url: String = match opts.get("url") {
-            Some(val) => String::from(val),
-            None => String::from("")
-        };
+/// Applies the manual `rotate` and `flip` options, so crop and size apply to the corrected image.
+fn transform(image: DynamicImage, opts: &ThumbOptions) -> DynamicImage {
+    let image = match opts.rotate {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image
+    };
 
-        let width: u32 = match opts.get("width") {
-            Some(val) => val.parse::<u32>().unwrap(),
-            None => 180
-        };
+    match opts.flip {
+        Some(Flip::Horizontal) => image.fliph(),
+        Some(Flip::Vertical) => image.flipv(),
+        None => image
+    }
+}
 
-        ThumbOptions {
-            url: url,
-            width: width
+/// Scales `length` by `target / reference`, never going below one pixel.
+fn scale(length: u32, target: u32, reference: u32) -> u32 {
+    if reference == 0 {
+        return length.max(1);
+    }
+
+    let scaled = f64::from(length) * f64::from(target) / f64::from(reference);
+    (scaled.round().min(f64::from(u32::MAX)) as u32).max(1)
+}
+
+/// Refuses images larger than the configured thumbnail size before anything is allocated for them.
+fn check_size(width: u32, height: u32, limits: &Limits) -> Result<(), ThumbError> {
+    if width > limits.max_width || height > limits.max_height {
+        return Err(ThumbError::ThumbnailTooLarge(width, height));
+    }
+
+    Ok(())
+}
+
+/// Resizes the decoded image to the requested box, cropping or letterboxing it for `cover` and `contain`.
+///
+/// Sizes following from the aspect ratio are checked against `limits` too, so an extreme source
+/// cannot make us allocate a huge image.
+fn fit_image(image: &DynamicImage, opts: &ThumbOptions, limits: &Limits) -> Result<RgbaImage, ThumbError> {
+    let (original_width, original_height) = image.dimensions();
+
+    let (width, height) = match (opts.width, opts.height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, scale(original_height, width, original_width)),
+        (None, Some(height)) => (scale(original_width, height, original_height), height),
+        (None, None) => (original_width, original_height)
+    };
+    check_size(width, height, limits)?;
+
+    if opts.width.is_none() || opts.height.is_none() || opts.fit == Fit::Fill {
+        return Ok(image::imageops::resize(image, width, height, opts.filter));
+    }
+
+    let width_ratio = f64::from(width) / f64::from(original_width);
+    let height_ratio = f64::from(height) / f64::from(original_height);
+    let fits_width = match opts.fit {
+        Fit::Contain | Fit::Inside => width_ratio <= height_ratio,
+        _ => width_ratio >= height_ratio
+    };
+
+    let (resized_width, resized_height) = if fits_width {
+        (width, scale(original_height, width, original_width))
+    } else {
+        (scale(original_width, height, original_height), height)
+    };
+
+    match opts.fit {
+        Fit::Contain => {
+            let resized = image::imageops::resize(image, resized_width, resized_height, opts.filter);
+            let mut canvas = RgbaImage::new(width, height);
+            let x = (width - resized_width.min(width)) / 2;
+            let y = (height - resized_height.min(height)) / 2;
+            image::imageops::overlay(&mut canvas, &resized, x, y);
+            Ok(canvas)
+        },
+        Fit::Cover => Ok(cover(image, opts, (width, height), (resized_width, resized_height))),
+        _ => {
+            check_size(resized_width, resized_height, limits)?;
+            Ok(image::imageops::resize(image, resized_width, resized_height, opts.filter))
         }
     }
 }
 
-fn querify(string: &str) -> HashMap<String, String> {
-    let mut acc: HashMap<String, String> = HashMap::new();
-    let pairs: Vec<&str> = string.split('&').collect();
-    for kv in pairs {
-        let mut it = kv.splitn(2, '=').take(2);
-        match (it.next(), it.next()) {
-            (Some("url"), Some(v)) => acc.insert(String::from("url"), v.to_string()),
-            (Some("width"), Some(v)) => acc.insert(String::from("width"), v.to_string()),
-            _ => continue,
-        };
+/// Crops `image` to the `fit=cover` box after resizing it to `resized`.
+///
+/// When that would upscale, the window is cut out of the source first, so the intermediate image
+/// is never larger than the source or the box.
+fn cover(image: &DynamicImage, opts: &ThumbOptions, (width, height): (u32, u32), (resized_width, resized_height): (u32, u32)) -> RgbaImage {
+    let (original_width, original_height) = image.dimensions();
+
+    if resized_width <= original_width && resized_height <= original_height {
+        let mut resized = image::imageops::resize(image, resized_width, resized_height, opts.filter);
+        let (x, y) = opts.gravity.offset(&resized, width, height);
+        return image::imageops::crop(&mut resized, x, y, width, height).to_image();
     }
-    acc
+
+    let window_width = scale(width, original_width, resized_width).min(original_width);
+    let window_height = scale(height, original_height, resized_height).min(original_height);
+    let mut source = image.to_rgba();
+    let (x, y) = opts.gravity.offset(&source, window_width, window_height);
+    let window = image::imageops::crop(&mut source, x, y, window_width, window_height).to_image();
+    image::imageops::resize(&window, width, height, opts.filter)
+}
+
+#[derive(Clone)]
+struct Thumbnail {
+    bytes: Vec<u8>,
+    format: OutputFormat
+}
+
+/// Everything the handlers share, built once at startup.
+struct AppState {
+    client: fetch::Client,
+    limits: Limits,
+    signer: Option<Signer>,
+    policy: SourcePolicy,
+    settings: FetchSettings,
+    pool: RenderPool,
+    cache: MemoryCache,
+    disk: Option<DiskCache>,
+    flights: SingleFlight<(Thumbnail, bool)>
 }
 
-impl From<&str> for ThumbOptions {
-    fn from(query_params: &str) -> Self {
-        let qs = querify(query_params);
-        ThumbOptions::new(qs)
+impl AppState {
+    fn new(config: Config) -> Result<AppState, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(AppState {
+            client: config.fetch.client(&config.source),
+            limits: config.limits,
+            signer: config.signer(),
+            policy: config.source,
+            settings: config.fetch,
+            pool: RenderPool::new(&config.render),
+            cache: MemoryCache::new(&config.cache),
+            disk: DiskCache::open(&config.cache)
+                .map_err(|err| format!("Failed opening disk cache: {}", err))?,
+            flights: SingleFlight::new()
+        })
     }
 }
 
-async fn handle_thumbnail(opts: ThumbOptions, client: reqwest::Client) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+fn encode(image: &RgbaImage, format: OutputFormat, opts: &ThumbOptions) -> Result<Vec<u8>, ThumbError> {
+    let (width, height) = image.dimensions();
+    let mut bytes: Vec<u8> = vec![];
+
+    match format {
+        OutputFormat::Png | OutputFormat::Auto => {
+            // Going through the png crate directly, image's PNGEncoder does not expose compression settings
+            let fout = BufWriter::new(&mut bytes);
+            let mut encoder = png::Encoder::new(fout, width, height);
+            encoder.set_color(png::ColorType::RGBA);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_compression(opts.compression.clone());
+            encoder.set_filter(opts.png_filter);
+            encoder.write_header()
+                .and_then(|mut writer| writer.write_image_data(image))
+                .map_err(|err| ThumbError::Encode(image::ImageError::IoError(err.into())))?;
+        },
+        OutputFormat::Jpeg => {
+            let rgb = image::DynamicImage::ImageRgba8(image.clone()).to_rgb();
+            image::jpeg::JPEGEncoder::new_with_quality(&mut bytes, opts.quality)
+                .encode(&rgb, width, height, ColorType::Rgb8)
+                .map_err(ThumbError::Encode)?;
+        },
+        OutputFormat::Gif => {
+            image::gif::Encoder::new(&mut bytes)
+                .encode_frame(image::Frame::new(image.clone()))
+                .map_err(ThumbError::Encode)?;
+        },
+        OutputFormat::Bmp => {
+            image::bmp::BMPEncoder::new(&mut bytes)
+                .encode(image, width, height, ColorType::Rgba8)
+                .map_err(ThumbError::Encode)?;
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Downloads the source image, returning it with the upstream `Content-Type`.
+async fn download(url: &str, state: &AppState) -> Result<(Vec<u8>, Option<String>), ThumbError> {
     #[cfg(debug_assertions)]
     let download_start = Instant::now();
 
-    let file = client.get(&opts.url)
-        .send()
-        .await
-        .expect("Failed sending request")
-        .bytes()
-        .await
-        .expect("Bytes unwrap err");
+    let response = fetch::fetch(&state.client, url, &state.policy, &state.settings).await?;
+
+    if !response.status().is_success() {
+        return Err(ThumbError::Upstream(response.status()));
+    }
+
+    let content_type = response.headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|val| val.to_str().ok())
+        .map(String::from);
+
+    let file = fetch::read_body(response, state.limits.max_download_bytes, &state.settings).await?;
     
     #[cfg(debug_assertions)]
     let download_duration = download_start.elapsed();
     #[cfg(debug_assertions)]
     println!("Download duration is {:?}", download_duration);
 
+    Ok((file, content_type))
+}
+
+async fn handle_thumbnail(opts: ThumbOptions, state: &AppState) -> Result<Thumbnail, ThumbError> {
+    // Sources are kept on disk too, so other sizes of the same image skip the download
+    let source_key = format!("source:{}", opts.url);
+    let cached = match &state.disk {
+        Some(disk) => disk.get(&source_key).await,
+        None => None
+    };
+
+    let (file, content_type) = match cached {
+        Some((content_type, file)) => (file, Some(content_type).filter(|val| !val.is_empty())),
+        None => {
+            let (file, content_type) = download(&opts.url, state).await?;
+            if let Some(disk) = &state.disk {
+                disk.insert(&source_key, content_type.as_deref().unwrap_or(""), &file).await;
+            }
+            (file, content_type)
+        }
+    };
+
+    let limits = state.limits;
+    state.pool.run(move || render(&file, content_type.as_deref(), &opts, limits)).await
+}
+
+/// Looks the thumbnail up in the memory and disk caches, rendering and caching it on a miss.
+/// The flag tells whether it came from a cache.
+async fn cached_thumbnail(opts: ThumbOptions, state: Arc<AppState>) -> Result<(Thumbnail, bool), Arc<ThumbError>> {
+    // Cached sources and thumbnails skip `fetch`, so the policy is checked here in case it was narrowed since
+    fetch::parse_source(&opts.url)
+        .and_then(|url| state.policy.check(&url))
+        .map_err(Arc::new)?;
+
+    let key = opts.cache_key();
+    if let Some(thumb) = state.cache.get(&key) {
+        return Ok((thumb, true));
+    }
+
+    // Concurrent misses for the same thumbnail wait for one disk lookup or render
+    let render = lookup_or_render(opts, key.clone(), state.clone())
+        .map(|result| result.map_err(Arc::new));
+    state.flights.run(key, render).await
+}
+
+async fn lookup_or_render(opts: ThumbOptions, key: String, state: Arc<AppState>) -> Result<(Thumbnail, bool), ThumbError> {
+    let disk_key = format!("thumb:{}", key);
+    if let Some(disk) = &state.disk {
+        if let Some((format, bytes)) = disk.get(&disk_key).await {
+            if let Ok(format) = format.parse() {
+                let thumb = Thumbnail { bytes, format };
+                state.cache.insert(key, thumb.clone());
+                return Ok((thumb, true));
+            }
+        }
+    }
+
+    let thumb = handle_thumbnail(opts, &state).await?;
+    if let Some(disk) = &state.disk {
+        disk.insert(&disk_key, &thumb.format.to_string(), &thumb.bytes).await;
+    }
+    state.cache.insert(key, thumb.clone());
+
+    Ok((thumb, false))
+}
+
+/// Decodes the source, applies `opts` and encodes the thumbnail. CPU-bound, see `RenderPool`.
+fn render(file: &[u8], content_type: Option<&str>, opts: &ThumbOptions, limits: Limits) -> Result<Thumbnail, ThumbError> {
     #[cfg(debug_assertions)]
     let render_start = Instant::now();
 
-    let width = opts.width;
-    let image = image::load_from_memory_with_format(&file, image::ImageFormat::Png).unwrap();
-    let original_width = image.width();
-    let ratio = original_width / width;
-    let original_height = image.height();
-    let height = original_height / ratio;
+    let format = detect_format(file, content_type)?;
 
-    let resized = image::imageops::resize(&image, width, height, FilterType::Nearest);
-    let mut bytes: Vec<u8> = vec![];
-    let fout = BufWriter::new(&mut bytes);
-    image::png::PNGEncoder::new(fout).encode(&resized, width, height, ColorType::Rgba8).unwrap();
+    // Only the header is read here, so a tiny file claiming huge dimensions is refused before allocating
+    let (width, height) = image::io::Reader::with_format(Cursor::new(file), format)
+        .into_dimensions()
+        .map_err(ThumbError::Decode)?;
+    if width > limits.max_source_width
+        || height > limits.max_source_height
+        || u64::from(width) * u64::from(height) > limits.max_source_pixels {
+        return Err(ThumbError::TooManyPixels(width, height));
+    }
+
+    let mut image = image::load_from_memory_with_format(file, format)
+        .map_err(ThumbError::Decode)?;
+
+    if opts.autorotate {
+        image = apply_orientation(image, exif_orientation(file));
+    }
+
+    image = transform(image, opts);
+
+    if let Some(crop) = &opts.crop {
+        let (x, y, width, height) = crop.to_pixels(image.width(), image.height())?;
+        image = image.crop(x, y, width, height);
+    }
+
+    let resized = fit_image(&image, opts, &limits)?;
+    // A negotiated JPEG would turn transparent areas, letterboxing included, black
+    let transparent = resized.pixels().any(|pixel| pixel[3] < u8::MAX);
+    let requested = match opts.transparent_format {
+        Some(requested) if transparent => requested,
+        _ => opts.format.unwrap_or(OutputFormat::Png)
+    };
+    let output_format = requested.resolve(format);
+    let bytes = encode(&resized, output_format, opts)?;
 
     #[cfg(debug_assertions)]
     let render_duration = render_start.elapsed();
     #[cfg(debug_assertions)]
     println!("Render duration {:?}", render_duration);
 
-    Ok(bytes)
+    Ok(Thumbnail {
+        bytes,
+        format: output_format
+    })
 }
 
-async fn router(req: Request<Body>, client: reqwest::Client) -> Result<Response<Body>, hyper::Error> {
-    let uri = req.uri();
+fn not_found() -> Response<Body> {
+    let mut not_found = Response::default();
+    *not_found.status_mut() = StatusCode::NOT_FOUND;
+    not_found
+}
 
-    match (req.method(), uri.path()) {
-        (&Method::GET, "/thumbnail") => {
-            let q = uri.query().unwrap();
+/// Parses the options of a `/thumbnail` request, checking its signature first when signing is enabled.
+fn parse_request(query: Option<&str>, path: Option<&str>, limits: &Limits, signer: Option<&Signer>) -> Result<ThumbOptions, ThumbError> {
+    match (path, signer) {
+        (Some(path), Some(signer)) => Ok(ThumbOptions::from_path(signer.verify_path(path)?, limits)?),
+        (Some(path), None) => Ok(ThumbOptions::from_path(path, limits)?),
+        (None, Some(signer)) => Ok(ThumbOptions::from_query(signer.verify_query(query.unwrap_or(""))?, limits)?),
+        (None, None) => Ok(ThumbOptions::from_query(query.unwrap_or(""), limits)?)
+    }
+}
 
-            let thumb = handle_thumbnail(ThumbOptions::from(q), client)
-                .await
-                .expect("Handling did not go that well");
+fn stats_json(stats: CacheStats) -> serde_json::Value {
+    serde_json::json!({
+        "hits": stats.hits,
+        "misses": stats.misses,
+        "entries": stats.entries,
+        "bytes": stats.bytes
+    })
+}
+
+/// Cache counters as JSON.
+fn stats(state: &AppState) -> Response<Body> {
+    let mut body = serde_json::json!({
+        "cache": stats_json(state.cache.stats())
+    });
 
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .body(Body::from(thumb))
-                .unwrap();
+    if let Some(disk) = &state.disk {
+        body["disk"] = stats_json(disk.stats());
+    }
 
-            Ok(response)
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+async fn router(req: Request<Body>, state: Arc<AppState>) -> Result<Response<Body>, hyper::Error> {
+    let uri = req.uri();
+
+    // Either `/thumbnail?url=...&width=...` or the path form `/t/{options}/{source}`
+    let path = match (req.method(), uri.path()) {
+        (&Method::GET, "/thumbnail") => None,
+        (&Method::GET, "/stats") => return Ok(stats(&state)),
+        (&Method::GET, path) => match path.strip_prefix("/thumbnail/").or_else(|| path.strip_prefix("/t/")) {
+            Some(rest) => Some(rest),
+            None => return Ok(not_found())
         },
-        _ => {
-            let mut not_found = Response::default();
-            *not_found.status_mut() = StatusCode::NOT_FOUND;
-            Ok(not_found)
-        }
+        _ => return Ok(not_found())
+    };
+
+    let mut opts = match parse_request(uri.query(), path, &state.limits, state.signer.as_ref()) {
+        Ok(opts) => opts,
+        Err(err) => return Ok(err.to_response())
+    };
+
+    let negotiated = opts.format.is_none();
+    if negotiated {
+        let accept = req.headers()
+            .get(hyper::header::ACCEPT)
+            .and_then(|val| val.to_str().ok())
+            .unwrap_or("");
+        opts.format = Some(negotiate_format(accept, false));
+        opts.transparent_format = Some(negotiate_format(accept, true));
     }
+
+    let (thumb, cached) = match cached_thumbnail(opts, state.clone()).await {
+        Ok(result) => result,
+        Err(err) => return Ok(err.to_response())
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, thumb.format.content_type())
+        .header("X-Cache", if cached { "HIT" } else { "MISS" });
+
+    if negotiated {
+        response = response.header(hyper::header::VARY, "Accept");
+    }
+
+    let response = response
+        .body(Body::from(thumb.bytes))
+        .unwrap();
+
+    Ok(response)
 }
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let client: reqwest::Client = reqwest::Client::new();
-    let cow_client: Cow<reqwest::Client> = Cow::Owned(client);
+    let (config, args) = Config::load(std::env::args().skip(1))?;
 
-    let make_svc = make_service_fn(|_conn| {
-        let cow_client = cow_client.clone();
-        async { 
-            let clone = cow_client.into_owned();
+    // `thumbnailer_rust sign <message>` prints the signature for a URL, see `Signer`
+    if args.len() == 2 && args[0] == "sign" {
+        let signer = config.signer().ok_or("signing_key is not configured")?;
+        println!("{}", signer.sign(&args[1]));
+        return Ok(());
+    }
 
+    let addr = SocketAddr::new(config.bind, config.port);
+    let state = Arc::new(AppState::new(config)?);
+
+    let make_svc = make_service_fn(move |_conn| {
+        let state = state.clone();
+        async move { 
             Ok::<_, Infallible>(service_fn(move |req| {
-                router(req, clone.to_owned())
+                router(req, state.clone())
             })) 
         }
     });
 
-    let addr = ([127, 0, 0, 1], 3000).into();
-
     let server = Server::bind(&addr).serve(make_svc);
 
     println!("Listening on http://{}", addr);
@@ -144,4 +525,62 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     server.await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fit(source: (u32, u32), query: &str) -> Result<(u32, u32), ThumbError> {
+        let limits = Limits::default();
+        let opts = ThumbOptions::from_query(&format!("url=http://example.com/a.png&{}", query), &limits).unwrap();
+        let image = DynamicImage::new_rgba8(source.0, source.1);
+        fit_image(&image, &opts, &limits).map(|resized| resized.dimensions())
+    }
+
+    #[test]
+    fn scale_lengths() {
+        assert_eq!(scale(600, 180, 1000), 108);
+        assert_eq!(scale(1, 180, 10000), 1);
+        assert_eq!(scale(10, 5, 0), 10);
+        assert_eq!(scale(u32::MAX, u32::MAX, 1), u32::MAX);
+    }
+
+    #[test]
+    fn fit_single_dimension() {
+        assert_eq!(fit((1000, 600), "").unwrap(), (180, 108));
+        assert_eq!(fit((1000, 600), "width=100").unwrap(), (100, 60));
+        assert_eq!(fit((1000, 600), "height=60").unwrap(), (100, 60));
+    }
+
+    #[test]
+    fn fit_modes() {
+        assert_eq!(fit((1000, 600), "width=100&height=100&fit=fill").unwrap(), (100, 100));
+        assert_eq!(fit((1000, 600), "width=100&height=100&fit=contain").unwrap(), (100, 100));
+        assert_eq!(fit((1000, 600), "width=100&height=100&fit=cover").unwrap(), (100, 100));
+        assert_eq!(fit((1000, 600), "width=100&height=100&fit=inside").unwrap(), (100, 60));
+        assert_eq!(fit((1000, 600), "width=100&height=100&fit=outside").unwrap(), (167, 100));
+    }
+
+    #[test]
+    fn fit_extreme_aspect_ratios() {
+        // The default width would make a 1.3 GB image of a tall sliver
+        assert!(matches!(fit((1, 10000), ""), Err(ThumbError::ThumbnailTooLarge(180, 1_800_000))));
+        assert!(matches!(fit((10000, 1), "height=100"), Err(ThumbError::ThumbnailTooLarge(1_000_000, 100))));
+        assert!(matches!(fit((10000, 1), "width=100&height=100&fit=outside"), Err(ThumbError::ThumbnailTooLarge(..))));
+
+        assert_eq!(fit((10000, 1), "width=100").unwrap(), (100, 1));
+        assert_eq!(fit((10000, 1), "width=100&height=100&fit=fill").unwrap(), (100, 100));
+        assert_eq!(fit((10000, 1), "width=100&height=100&fit=inside").unwrap(), (100, 1));
+        assert_eq!(fit((1, 10000), "width=100&height=100&fit=contain").unwrap(), (100, 100));
+    }
+
+    #[test]
+    fn fit_cover_extreme_aspect_ratios() {
+        // Resizing before cropping would need a 10,000,000x1000 image here
+        assert_eq!(fit((10000, 1), "width=1000&height=1000&fit=cover").unwrap(), (1000, 1000));
+        assert_eq!(fit((1, 10000), "width=1000&height=1000&fit=cover&gravity=south").unwrap(), (1000, 1000));
+        assert_eq!(fit((10, 1000), "width=100&height=100&fit=cover&gravity=entropy").unwrap(), (100, 100));
+        assert_eq!(fit((1000, 10), "width=100&height=100&fit=cover&gravity=attention").unwrap(), (100, 100));
+    }
+}
@@ -0,0 +1,958 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use image::{ImageFormat, RgbaImage};
+use image::imageops::FilterType;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use url::form_urlencoded;
+use crate::config::Overrides;
+use crate::error::ParamError;
+use crate::smartcrop;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Auto,
+    Png,
+    Jpeg,
+    Gif,
+    Bmp
+}
+
+impl OutputFormat {
+    /// Resolves `Auto` to the source format, falling back to PNG when we cannot encode the source format.
+    pub fn resolve(self, source: ImageFormat) -> OutputFormat {
+        match self {
+            OutputFormat::Auto => match source {
+                ImageFormat::Png => OutputFormat::Png,
+                ImageFormat::Jpeg => OutputFormat::Jpeg,
+                ImageFormat::Gif => OutputFormat::Gif,
+                ImageFormat::Bmp => OutputFormat::Bmp,
+                _ => OutputFormat::Png
+            },
+            format => format
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png | OutputFormat::Auto => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Gif => "image/gif",
+            OutputFormat::Bmp => "image/bmp"
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(OutputFormat::Auto),
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "gif" => Ok(OutputFormat::Gif),
+            "bmp" => Ok(OutputFormat::Bmp),
+            _ => Err(format!("Unknown output format: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Auto => "auto",
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Bmp => "bmp"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Formats we can encode, in the order we prefer them when the client accepts several equally.
+const NEGOTIABLE_FORMATS: [OutputFormat; 4] = [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Gif, OutputFormat::Bmp];
+
+/// Picks the output format from an `Accept` header value.
+///
+/// Every format gets the quality of the most specific media range matching it. When only `*/*` matches,
+/// PNG is kept as the historical default, and PNG is also the fallback when nothing we encode is acceptable.
+/// For `transparent` thumbnails JPEG, which has no alpha channel, is only picked when nothing else is acceptable.
+pub fn negotiate_format(accept: &str, transparent: bool) -> OutputFormat {
+    let ranges: Vec<(String, f32)> = accept.split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let mime = parts.next()?.trim().to_ascii_lowercase();
+            if mime.is_empty() {
+                return None;
+            }
+
+            let q = parts
+                .filter_map(|param| {
+                    let mut kv = param.splitn(2, '=');
+                    match (kv.next().map(str::trim), kv.next()) {
+                        (Some("q"), Some(v)) => v.trim().parse::<f32>().ok(),
+                        _ => None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+
+            Some((mime, q))
+        })
+        .collect();
+
+    let mut best: Option<(OutputFormat, f32, u8)> = None;
+    for &format in NEGOTIABLE_FORMATS.iter() {
+        if transparent && format == OutputFormat::Jpeg {
+            continue;
+        }
+
+        let content_type = format.content_type();
+        let matched = ranges.iter()
+            .filter_map(|(mime, q)| {
+                let specificity = if mime == content_type {
+                    2
+                } else if mime == "image/*" {
+                    1
+                } else if mime == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                Some((specificity, *q))
+            })
+            .max_by_key(|(specificity, _)| *specificity);
+
+        let (specificity, q) = match matched {
+            Some(matched) if matched.1 > 0.0 => matched,
+            _ => continue
+        };
+
+        let better = match best {
+            None => true,
+            Some((best_format, best_q, best_specificity)) => {
+                q > best_q
+                    || (q == best_q && specificity > best_specificity)
+                    || (q == best_q && specificity == 0 && best_specificity == 0
+                        && format == OutputFormat::Png && best_format != OutputFormat::Png)
+            }
+        };
+
+        if better {
+            best = Some((format, q, specificity));
+        }
+    }
+
+    match best {
+        Some((format, _, _)) => format,
+        None if transparent => negotiate_format(accept, false),
+        None => OutputFormat::Png
+    }
+}
+
+fn parse_range(param: &'static str, val: &str, min: u32, max: u32) -> Result<u32, ParamError> {
+    let parsed = val.parse::<u32>()
+        .map_err(|_| ParamError::Invalid { param, value: val.to_string() })?;
+
+    if parsed < min || parsed > max {
+        return Err(ParamError::OutOfRange { param, value: val.to_string(), min, max });
+    }
+
+    Ok(parsed)
+}
+
+fn validate_url(param: &'static str, val: &str) -> Result<String, ParamError> {
+    if val.is_empty() {
+        return Err(ParamError::Missing { param });
+    }
+
+    match url::Url::parse(val) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(String::from(val)),
+        _ => Err(ParamError::Invalid { param, value: val.to_string() })
+    }
+}
+
+fn parse_bool(param: &'static str, val: &str) -> Result<bool, ParamError> {
+    match val.to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(ParamError::Invalid { param, value: val.to_string() })
+    }
+}
+
+fn parse_compression(val: &str) -> Result<png::Compression, ParamError> {
+    match val.to_ascii_lowercase().as_str() {
+        "fast" => Ok(png::Compression::Fast),
+        "default" => Ok(png::Compression::Default),
+        "best" => Ok(png::Compression::Best),
+        _ => Err(ParamError::Invalid { param: "compression", value: val.to_string() })
+    }
+}
+
+fn compression_name(compression: &png::Compression) -> &'static str {
+    match compression {
+        png::Compression::Fast => "fast",
+        png::Compression::Default => "default",
+        png::Compression::Best => "best",
+        png::Compression::Huffman => "huffman",
+        png::Compression::Rle => "rle"
+    }
+}
+
+fn parse_png_filter(val: &str) -> Result<png::FilterType, ParamError> {
+    match val.to_ascii_lowercase().as_str() {
+        "none" => Ok(png::FilterType::NoFilter),
+        "sub" => Ok(png::FilterType::Sub),
+        "up" => Ok(png::FilterType::Up),
+        "avg" => Ok(png::FilterType::Avg),
+        "paeth" => Ok(png::FilterType::Paeth),
+        _ => Err(ParamError::Invalid { param: "png_filter", value: val.to_string() })
+    }
+}
+
+fn png_filter_name(filter: png::FilterType) -> &'static str {
+    match filter {
+        png::FilterType::NoFilter => "none",
+        png::FilterType::Sub => "sub",
+        png::FilterType::Up => "up",
+        png::FilterType::Avg => "avg",
+        png::FilterType::Paeth => "paeth"
+    }
+}
+
+fn parse_filter(val: &str) -> Result<FilterType, ParamError> {
+    match val.to_ascii_lowercase().as_str() {
+        "nearest" => Ok(FilterType::Nearest),
+        "triangle" => Ok(FilterType::Triangle),
+        "catmullrom" => Ok(FilterType::CatmullRom),
+        "gaussian" => Ok(FilterType::Gaussian),
+        "lanczos3" => Ok(FilterType::Lanczos3),
+        _ => Err(ParamError::Invalid { param: "filter", value: val.to_string() })
+    }
+}
+
+fn filter_name(filter: FilterType) -> &'static str {
+    match filter {
+        FilterType::Nearest => "nearest",
+        FilterType::Triangle => "triangle",
+        FilterType::CatmullRom => "catmullrom",
+        FilterType::Gaussian => "gaussian",
+        FilterType::Lanczos3 => "lanczos3"
+    }
+}
+
+fn parse_rotate(val: &str) -> Result<u32, ParamError> {
+    match val.trim() {
+        "0" => Ok(0),
+        "90" => Ok(90),
+        "180" => Ok(180),
+        "270" => Ok(270),
+        _ => Err(ParamError::Invalid { param: "rotate", value: val.to_string() })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flip {
+    Horizontal,
+    Vertical
+}
+
+impl FromStr for Flip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "h" | "horizontal" => Ok(Flip::Horizontal),
+            "v" | "vertical" => Ok(Flip::Vertical),
+            _ => Err(format!("Unknown flip: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Flip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Flip::Horizontal => "h",
+            Flip::Vertical => "v"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A crop coordinate, either in pixels or as a percentage of the source dimension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Pixels(u32),
+    Percent(f64)
+}
+
+impl Length {
+    pub fn to_pixels(self, dimension: u32) -> u32 {
+        match self {
+            Length::Pixels(pixels) => pixels,
+            Length::Percent(percent) => (f64::from(dimension) * percent / 100.0).round() as u32
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            match percent.parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(Length::Percent(percent)),
+                _ => Err(format!("Invalid percentage: {}", s))
+            }
+        } else {
+            s.parse::<u32>()
+                .map(Length::Pixels)
+                .map_err(|_| format!("Invalid length: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Length::Pixels(pixels) => write!(f, "{}", pixels),
+            Length::Percent(percent) => write!(f, "{}%", percent)
+        }
+    }
+}
+
+/// Region extracted from the source before resizing, given as `crop=x,y,w,h`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CropRegion {
+    x: Length,
+    y: Length,
+    width: Length,
+    height: Length,
+    raw: String
+}
+
+/// Start and length in pixels of one axis of a crop region.
+///
+/// When both are percentages the two edges are rounded rather than the length, so `50%,50%`
+/// ends exactly at the edge of an odd-sized image instead of one pixel past it.
+fn span(start: Length, length: Length, dimension: u32) -> (u32, u32) {
+    let offset = start.to_pixels(dimension);
+    let length = match (start, length) {
+        (Length::Percent(start), Length::Percent(length)) => {
+            Length::Percent(start + length).to_pixels(dimension).saturating_sub(offset)
+        },
+        _ => length.to_pixels(dimension)
+    };
+    (offset, length)
+}
+
+impl CropRegion {
+    /// Resolves the region to pixels, failing when it is empty or reaches outside the source.
+    pub fn to_pixels(&self, image_width: u32, image_height: u32) -> Result<(u32, u32, u32, u32), ParamError> {
+        let (x, width) = span(self.x, self.width, image_width);
+        let (y, height) = span(self.y, self.height, image_height);
+
+        let fits = width > 0 && height > 0
+            && u64::from(x) + u64::from(width) <= u64::from(image_width)
+            && u64::from(y) + u64::from(height) <= u64::from(image_height);
+
+        if !fits {
+            return Err(ParamError::OutOfBounds {
+                param: "crop",
+                value: self.raw.clone(),
+                width: image_width,
+                height: image_height
+            });
+        }
+
+        Ok((x, y, width, height))
+    }
+}
+
+impl FromStr for CropRegion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(',')
+            .map(str::parse::<Length>)
+            .collect::<Result<Vec<Length>, String>>()?;
+
+        match parts.as_slice() {
+            [x, y, width, height] => Ok(CropRegion {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+                raw: s.to_string()
+            }),
+            _ => Err(format!("Expected x,y,w,h, got {}", s))
+        }
+    }
+}
+
+/// How the image is fitted into the requested box when both `width` and `height` are given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fit {
+    /// Preserve aspect ratio and letterbox into the exact box
+    Contain,
+    /// Preserve aspect ratio and crop to the exact box
+    Cover,
+    /// Stretch to the exact box
+    Fill,
+    /// Preserve aspect ratio, as large as possible within the box
+    Inside,
+    /// Preserve aspect ratio, as small as possible while covering the box
+    Outside
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "contain" => Ok(Fit::Contain),
+            "cover" => Ok(Fit::Cover),
+            "fill" => Ok(Fit::Fill),
+            "inside" => Ok(Fit::Inside),
+            "outside" => Ok(Fit::Outside),
+            _ => Err(format!("Unknown fit: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Fit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+            Fit::Inside => "inside",
+            Fit::Outside => "outside"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Which part of the image is kept when `fit=cover` crops it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+    /// Window with the most detail, measured by luminance entropy
+    Entropy,
+    /// Window with the most edges, saturated colours and skin tones
+    Attention
+}
+
+impl Gravity {
+    /// Top-left corner of the crop window for `image` cropped down to `width`x`height`.
+    pub fn offset(self, image: &RgbaImage, width: u32, height: u32) -> (u32, u32) {
+        let (image_width, image_height) = image.dimensions();
+        let free_x = image_width - width.min(image_width);
+        let free_y = image_height - height.min(image_height);
+
+        let (x, y) = match self {
+            Gravity::Center => (free_x / 2, free_y / 2),
+            Gravity::North => (free_x / 2, 0),
+            Gravity::South => (free_x / 2, free_y),
+            Gravity::East => (free_x, free_y / 2),
+            Gravity::West => (0, free_y / 2),
+            Gravity::NorthEast => (free_x, 0),
+            Gravity::NorthWest => (0, 0),
+            Gravity::SouthEast => (free_x, free_y),
+            Gravity::SouthWest => (0, free_y),
+            Gravity::Entropy => smartcrop::entropy_offset(image, width, height),
+            Gravity::Attention => smartcrop::attention_offset(image, width, height)
+        };
+        (x, y)
+    }
+}
+
+impl FromStr for Gravity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "center" | "centre" => Ok(Gravity::Center),
+            "north" | "n" => Ok(Gravity::North),
+            "south" | "s" => Ok(Gravity::South),
+            "east" | "e" => Ok(Gravity::East),
+            "west" | "w" => Ok(Gravity::West),
+            "northeast" | "ne" => Ok(Gravity::NorthEast),
+            "northwest" | "nw" => Ok(Gravity::NorthWest),
+            "southeast" | "se" => Ok(Gravity::SouthEast),
+            "southwest" | "sw" => Ok(Gravity::SouthWest),
+            "entropy" => Ok(Gravity::Entropy),
+            "attention" => Ok(Gravity::Attention),
+            _ => Err(format!("Unknown gravity: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Gravity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Gravity::Center => "center",
+            Gravity::North => "north",
+            Gravity::South => "south",
+            Gravity::East => "east",
+            Gravity::West => "west",
+            Gravity::NorthEast => "northeast",
+            Gravity::NorthWest => "northwest",
+            Gravity::SouthEast => "southeast",
+            Gravity::SouthWest => "southwest",
+            Gravity::Entropy => "entropy",
+            Gravity::Attention => "attention"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Upper bounds for the requested thumbnail size.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Width used when neither `width` nor `height` is requested
+    pub default_width: u32,
+    /// Largest source body downloaded, in bytes
+    pub max_download_bytes: u64,
+    /// Largest source dimensions decoded, checked against the image header before decoding
+    pub max_source_width: u32,
+    pub max_source_height: u32,
+    pub max_source_pixels: u64
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 4096,
+            max_height: 4096,
+            default_width: 180,
+            max_download_bytes: 20 * 1024 * 1024,
+            max_source_width: 10_000,
+            max_source_height: 10_000,
+            max_source_pixels: 40_000_000
+        }
+    }
+}
+
+impl Limits {
+    /// Applies `THUMBNAILER_MAX_WIDTH`, `THUMBNAILER_MAX_HEIGHT`, `THUMBNAILER_DEFAULT_WIDTH`,
+    /// `THUMBNAILER_MAX_DOWNLOAD_BYTES` and `THUMBNAILER_MAX_SOURCE_WIDTH` / `_HEIGHT` / `_PIXELS`.
+    pub fn apply(&mut self, vars: &Overrides) -> Result<(), String> {
+        if let Some(val) = vars.parse("THUMBNAILER_MAX_WIDTH")? {
+            self.max_width = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_MAX_HEIGHT")? {
+            self.max_height = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_DEFAULT_WIDTH")? {
+            self.default_width = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_MAX_DOWNLOAD_BYTES")? {
+            self.max_download_bytes = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_MAX_SOURCE_WIDTH")? {
+            self.max_source_width = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_MAX_SOURCE_HEIGHT")? {
+            self.max_source_height = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_MAX_SOURCE_PIXELS")? {
+            self.max_source_pixels = val;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ThumbOptions {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub autorotate: bool,
+    pub rotate: u32,
+    pub flip: Option<Flip>,
+    pub crop: Option<CropRegion>,
+    pub fit: Fit,
+    pub gravity: Gravity,
+    pub filter: FilterType,
+    pub format: Option<OutputFormat>,
+    /// Replaces a negotiated `format` for thumbnails with transparent pixels
+    pub transparent_format: Option<OutputFormat>,
+    pub quality: u8,
+    pub compression: png::Compression,
+    pub png_filter: png::FilterType
+}
+
+impl ThumbOptions {
+    fn new(opts: HashMap<String, String>, limits: &Limits) -> Result<ThumbOptions, ParamError> {
+        let url: String = match (opts.get("url"), opts.get("url_b64")) {
+            (Some(_), Some(_)) => {
+                return Err(ParamError::Invalid { param: "url_b64", value: String::from("given together with url") })
+            },
+            (Some(val), None) => validate_url("url", val)?,
+            (None, Some(val)) => {
+                // Padding is optional, so both `aHR0cA` and `aHR0cA==` decode
+                let decoded = base64::decode_config(val.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .ok_or_else(|| ParamError::Invalid { param: "url_b64", value: val.to_string() })?;
+                validate_url("url_b64", &decoded)?
+            },
+            (None, None) => return Err(ParamError::Missing { param: "url" })
+        };
+
+        let height: Option<u32> = match opts.get("height") {
+            Some(val) => Some(parse_range("height", val, 1, limits.max_height)?),
+            None => None
+        };
+
+        let width: Option<u32> = match (opts.get("width"), height) {
+            (Some(val), _) => Some(parse_range("width", val, 1, limits.max_width)?),
+            (None, Some(_)) => None,
+            (None, None) => Some(limits.default_width)
+        };
+
+        let autorotate: bool = match opts.get("autorotate") {
+            Some(val) => parse_bool("autorotate", val)?,
+            None => true
+        };
+
+        // Clockwise degrees, applied after the EXIF orientation
+        let rotate: u32 = match opts.get("rotate") {
+            Some(val) => parse_rotate(val)?,
+            None => 0
+        };
+
+        let flip: Option<Flip> = match opts.get("flip") {
+            Some(val) => Some(val.parse::<Flip>()
+                .map_err(|_| ParamError::Invalid { param: "flip", value: val.to_string() })?),
+            None => None
+        };
+
+        let crop: Option<CropRegion> = match opts.get("crop") {
+            Some(val) => Some(val.parse::<CropRegion>()
+                .map_err(|_| ParamError::Invalid { param: "crop", value: val.to_string() })?),
+            None => None
+        };
+
+        let fit: Fit = match opts.get("fit") {
+            Some(val) => val.parse::<Fit>()
+                .map_err(|_| ParamError::Invalid { param: "fit", value: val.to_string() })?,
+            None => Fit::Inside
+        };
+
+        let gravity: Gravity = match opts.get("gravity") {
+            Some(val) => val.parse::<Gravity>()
+                .map_err(|_| ParamError::Invalid { param: "gravity", value: val.to_string() })?,
+            None => Gravity::Center
+        };
+
+        let filter: FilterType = match opts.get("filter") {
+            Some(val) => parse_filter(val)?,
+            None => FilterType::Lanczos3
+        };
+
+        // Left empty so the router can negotiate it from the Accept header
+        let format: Option<OutputFormat> = match opts.get("format") {
+            Some(val) => Some(val.parse::<OutputFormat>()
+                .map_err(|_| ParamError::Invalid { param: "format", value: val.to_string() })?),
+            None => None
+        };
+
+        // JPEG quality, the image crate defaults to 75
+        let quality: u8 = match opts.get("quality") {
+            Some(val) => parse_range("quality", val, 1, 100)? as u8,
+            None => 75
+        };
+
+        // Fast compression with the Sub filter is what the png crate picks by default
+        let compression: png::Compression = match opts.get("compression") {
+            Some(val) => parse_compression(val)?,
+            None => png::Compression::Fast
+        };
+
+        let png_filter: png::FilterType = match opts.get("png_filter") {
+            Some(val) => parse_png_filter(val)?,
+            None => png::FilterType::Sub
+        };
+
+        Ok(ThumbOptions {
+            url,
+            width,
+            height,
+            autorotate,
+            rotate,
+            flip,
+            crop,
+            fit,
+            gravity,
+            filter,
+            format,
+            transparent_format: None,
+            quality,
+            compression,
+            png_filter
+        })
+    }
+}
+
+/// Every query parameter `ThumbOptions::new` understands, anything else is ignored.
+const OPTION_KEYS: [&str; 15] = [
+    "url", "url_b64", "width", "height", "autorotate", "rotate", "flip", "crop", "fit",
+    "gravity", "filter", "format", "quality", "compression", "png_filter"
+];
+
+/// Parses an `application/x-www-form-urlencoded` query string, percent-decoding keys and values.
+///
+/// Only documented options are kept, and when a key is repeated the last occurrence wins.
+fn querify(string: &str) -> HashMap<String, String> {
+    let mut acc: HashMap<String, String> = HashMap::new();
+    for (key, value) in form_urlencoded::parse(string.as_bytes()) {
+        if OPTION_KEYS.contains(&key.as_ref()) {
+            acc.insert(key.into_owned(), value.into_owned());
+        }
+    }
+    acc
+}
+
+/// Long option name for the short keys accepted in path options, e.g. `w` for `width`.
+fn expand_key(key: &str) -> &str {
+    match key {
+        "w" => "width",
+        "h" => "height",
+        "q" => "quality",
+        "f" => "format",
+        "g" => "gravity",
+        "r" => "rotate",
+        "c" => "compression",
+        key => key
+    }
+}
+
+fn percent_decode(param: &'static str, val: &str) -> Result<String, ParamError> {
+    percent_decode_str(val)
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+        .map_err(|_| ParamError::Invalid { param, value: val.to_string() })
+}
+
+/// Parses the path form `{options}/{source}`, e.g. `w_300,h_200,fit_cover/aHR0cHM6Ly9...`.
+///
+/// Options are comma separated `key_value` pairs with the same names as the query parameters
+/// (plus the short keys from `expand_key`); `crop` takes its coordinates separated by colons.
+/// The source is either a percent-encoded `http(s)://` URL or a base64url encoded one.
+fn pathify(path: &str) -> Result<HashMap<String, String>, ParamError> {
+    let mut acc: HashMap<String, String> = HashMap::new();
+    let mut segments = path.splitn(2, '/');
+    let options = percent_decode("options", segments.next().unwrap_or(""))?;
+    let source = segments.next().unwrap_or("");
+
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        let mut kv = option.rsplitn(2, '_');
+        let (value, key) = match (kv.next(), kv.next()) {
+            (Some(value), Some(key)) => (value, expand_key(key)),
+            _ => return Err(ParamError::Invalid { param: "options", value: option.to_string() })
+        };
+
+        // Checked by the signature verification, see `Signer::verify_path`
+        if key == "expires" {
+            continue;
+        }
+
+        if key == "url" || key == "url_b64" || !OPTION_KEYS.contains(&key) {
+            return Err(ParamError::Invalid { param: "options", value: option.to_string() });
+        }
+
+        let value = if key == "crop" { value.replace(':', ",") } else { value.to_string() };
+        acc.insert(key.to_string(), value);
+    }
+
+    let decoded = percent_decode("url", source)?;
+    if decoded.starts_with("http://") || decoded.starts_with("https://") {
+        acc.insert(String::from("url"), decoded);
+    } else if !source.is_empty() {
+        acc.insert(String::from("url_b64"), source.to_string());
+    }
+
+    Ok(acc)
+}
+
+impl ThumbOptions {
+    pub fn from_query(query_params: &str, limits: &Limits) -> Result<ThumbOptions, ParamError> {
+        let qs = querify(query_params);
+        ThumbOptions::new(qs, limits)
+    }
+
+    pub fn from_path(path: &str, limits: &Limits) -> Result<ThumbOptions, ParamError> {
+        let opts = pathify(path)?;
+        ThumbOptions::new(opts, limits)
+    }
+
+    /// Identifies the thumbnail these options render. Built from the parsed values, so requests
+    /// spelling the same options differently (aliases, order, query or path form) share a key.
+    pub fn cache_key(&self) -> String {
+        fn optional<T: fmt::Display>(value: &Option<T>) -> String {
+            value.as_ref().map(T::to_string).unwrap_or_default()
+        }
+
+        let crop = match &self.crop {
+            Some(crop) => format!("{},{},{},{}", crop.x, crop.y, crop.width, crop.height),
+            None => String::new()
+        };
+
+        // The URL goes last so it cannot be mistaken for any of the fixed fields before it
+        format!(
+            "w={};h={};autorotate={};rotate={};flip={};crop={};fit={};gravity={};filter={};format={};transparent_format={};quality={};compression={};png_filter={};url={}",
+            optional(&self.width),
+            optional(&self.height),
+            self.autorotate,
+            self.rotate,
+            optional(&self.flip),
+            crop,
+            self.fit,
+            self.gravity,
+            filter_name(self.filter),
+            optional(&self.format),
+            optional(&self.transparent_format),
+            self.quality,
+            compression_name(&self.compression),
+            png_filter_name(self.png_filter),
+            self.url
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(query: &str) -> String {
+        ThumbOptions::from_query(query, &Limits::default()).unwrap().cache_key()
+    }
+
+    #[test]
+    fn cache_key_ignores_spelling() {
+        assert_eq!(
+            key("url=http://example.com/a.png&width=100&gravity=ne&flip=horizontal&crop=0,0,50%25,50%25"),
+            key("crop=0,0,50.0%25,50%25&flip=h&gravity=northeast&width=100&url=http://example.com/a.png")
+        );
+    }
+
+    #[test]
+    fn cache_key_covers_options() {
+        let base = key("url=http://example.com/a.png&width=100");
+        assert_ne!(base, key("url=http://example.com/a.png&width=101"));
+        assert_ne!(base, key("url=http://example.com/b.png&width=100"));
+        assert_ne!(base, key("url=http://example.com/a.png&width=100&format=jpeg"));
+        assert_ne!(base, key("url=http://example.com/a.png&width=100&crop=0,0,10,10"));
+        assert_ne!(base, key("url=http://example.com/a.png&width=100&compression=best"));
+    }
+
+    #[test]
+    fn negotiate_without_preferences() {
+        assert_eq!(negotiate_format("", false), OutputFormat::Png);
+        assert_eq!(negotiate_format("*/*", false), OutputFormat::Png);
+        assert_eq!(negotiate_format("text/html", false), OutputFormat::Png);
+    }
+
+    #[test]
+    fn negotiate_browser_accept() {
+        let accept = "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+        assert_eq!(negotiate_format(accept, false), OutputFormat::Jpeg);
+        assert_eq!(negotiate_format(accept, true), OutputFormat::Png);
+    }
+
+    #[test]
+    fn negotiate_by_quality() {
+        assert_eq!(negotiate_format("image/png;q=0.5, image/gif", false), OutputFormat::Gif);
+        assert_eq!(negotiate_format("image/*;q=0.5, image/png", false), OutputFormat::Png);
+        assert_eq!(negotiate_format("image/gif;q=0.8, */*;q=0.9", false), OutputFormat::Png);
+        assert_eq!(negotiate_format("image/bmp; q=0.1", false), OutputFormat::Bmp);
+    }
+
+    #[test]
+    fn negotiate_specific_range_wins() {
+        // The most specific matching range decides the quality, even when it is lower
+        assert_eq!(negotiate_format("image/*, image/jpeg;q=0", false), OutputFormat::Png);
+        assert_eq!(negotiate_format("image/*;q=0.9, image/jpeg;q=0.5", false), OutputFormat::Png);
+        // Equal quality goes to the more specific range
+        assert_eq!(negotiate_format("image/*;q=0.9, image/gif;q=0.9", false), OutputFormat::Gif);
+        // Equally specific and equally good formats keep our preference order
+        assert_eq!(negotiate_format("image/png, image/jpeg", false), OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn negotiate_transparent() {
+        assert_eq!(negotiate_format("image/*", true), OutputFormat::Png);
+        assert_eq!(negotiate_format("image/jpeg, image/gif;q=0.5", true), OutputFormat::Gif);
+        // JPEG is still better than something the client did not ask for
+        assert_eq!(negotiate_format("image/jpeg", true), OutputFormat::Jpeg);
+    }
+
+    fn crop(region: &str, width: u32, height: u32) -> Result<(u32, u32, u32, u32), ParamError> {
+        region.parse::<CropRegion>().unwrap().to_pixels(width, height)
+    }
+
+    #[test]
+    fn crop_parsing() {
+        let region: CropRegion = "10, 20,50%,100%".parse().unwrap();
+        assert_eq!(region.x, Length::Pixels(10));
+        assert_eq!(region.y, Length::Pixels(20));
+        assert_eq!(region.width, Length::Percent(50.0));
+        assert_eq!(region.height, Length::Percent(100.0));
+
+        assert!("10,20,30".parse::<CropRegion>().is_err());
+        assert!("10,20,30,40,50".parse::<CropRegion>().is_err());
+        assert!("a,20,30,40".parse::<CropRegion>().is_err());
+        assert!("-1,20,30,40".parse::<CropRegion>().is_err());
+        assert!("0,0,101%,50%".parse::<CropRegion>().is_err());
+    }
+
+    #[test]
+    fn crop_in_pixels() {
+        assert_eq!(crop("10,20,30,40", 100, 100).unwrap(), (10, 20, 30, 40));
+        assert_eq!(crop("0,0,100,100", 100, 100).unwrap(), (0, 0, 100, 100));
+        assert!(crop("10,0,91,100", 100, 100).is_err());
+        assert!(crop("0,0,0,10", 100, 100).is_err());
+        assert!(crop("100,0,1,1", 100, 100).is_err());
+    }
+
+    #[test]
+    fn crop_in_percent_on_odd_sizes() {
+        assert_eq!(crop("50%,0,50%,100%", 101, 101).unwrap(), (51, 0, 50, 101));
+        assert_eq!(crop("0,0,50%,100%", 101, 101).unwrap(), (0, 0, 51, 101));
+        assert_eq!(crop("25%,25%,50%,50%", 99, 33).unwrap(), (25, 8, 49, 17));
+        assert_eq!(crop("33.3%,0,66.7%,100%", 7, 7).unwrap(), (2, 0, 5, 7));
+        assert!(crop("60%,0,50%,100%", 101, 101).is_err());
+        assert!(crop("0,0,0.1%,100%", 101, 101).is_err());
+    }
+
+    #[test]
+    fn crop_mixing_units() {
+        assert_eq!(crop("10,0,50%,100%", 101, 50).unwrap(), (10, 0, 51, 50));
+        assert_eq!(crop("50%,0,50,100%", 101, 50).unwrap(), (51, 0, 50, 50));
+        assert!(crop("50%,0,51,100%", 101, 50).is_err());
+    }
+}
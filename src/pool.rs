@@ -0,0 +1,96 @@
+use std::sync::Arc;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use crate::config::Overrides;
+use crate::error::ThumbError;
+
+/// Size of the render pool.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolSettings {
+    /// Renders running at the same time
+    pub workers: usize,
+    /// Renders waiting for a worker before further requests are refused
+    pub queue: usize
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        PoolSettings {
+            workers: 4,
+            queue: 64
+        }
+    }
+}
+
+impl PoolSettings {
+    /// Applies `THUMBNAILER_RENDER_WORKERS` and `THUMBNAILER_RENDER_QUEUE`.
+    pub fn apply(&mut self, vars: &Overrides) -> Result<(), String> {
+        if let Some(val) = vars.parse("THUMBNAILER_RENDER_WORKERS")? {
+            self.workers = val;
+        }
+
+        if let Some(val) = vars.parse("THUMBNAILER_RENDER_QUEUE")? {
+            self.queue = val;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the CPU-bound image work on tokio's blocking threads, so it never stalls the reactor.
+///
+/// At most `workers` jobs run at once and `queue` more may wait for a slot, anything beyond
+/// that is refused with `ThumbError::Overloaded`.
+#[derive(Clone)]
+pub struct RenderPool {
+    workers: Arc<Semaphore>,
+    admitted: Arc<Semaphore>
+}
+
+/// Hands the slots of a job back once it is done, even if it panicked.
+struct Release {
+    workers: Arc<Semaphore>,
+    admitted: Arc<Semaphore>
+}
+
+impl Drop for Release {
+    fn drop(&mut self) {
+        self.workers.add_permits(1);
+        self.admitted.add_permits(1);
+    }
+}
+
+impl RenderPool {
+    pub fn new(settings: &PoolSettings) -> RenderPool {
+        RenderPool {
+            workers: Arc::new(Semaphore::new(settings.workers)),
+            admitted: Arc::new(Semaphore::new(settings.workers + settings.queue))
+        }
+    }
+
+    pub async fn run<T, F>(&self, job: F) -> Result<T, ThumbError>
+    where
+        F: FnOnce() -> Result<T, ThumbError> + Send + 'static,
+        T: Send + 'static
+    {
+        let admitted = self.admitted.try_acquire().map_err(|_| ThumbError::Overloaded)?;
+        let worker = self.workers.acquire().await;
+
+        // A started job runs to the end even when the request goes away, so its slots
+        // are released by the job itself rather than by the permits
+        admitted.forget();
+        worker.forget();
+        let release = Release {
+            workers: self.workers.clone(),
+            admitted: self.admitted.clone()
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let _release = release;
+            job()
+        })
+        .await
+        .map_err(|_| ThumbError::Panicked)?
+    }
+}
@@ -0,0 +1,210 @@
+use std::error::Error;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::form_urlencoded;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Missing,
+    Invalid,
+    Expired
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "Request is not signed"),
+            SignatureError::Invalid => write!(f, "Invalid signature"),
+            SignatureError::Expired => write!(f, "Signature expired")
+        }
+    }
+}
+
+impl Error for SignatureError {}
+
+/// Verifies HMAC-SHA256 signatures of request URLs.
+///
+/// Query URLs are signed over everything after `?` up to the trailing `&signature=...`,
+/// path URLs (`/t/{signature}/{options}/{source}`) over `{options}/{source}`. The signature
+/// is the base64url-encoded MAC without padding. An `expires` unix timestamp, given as a query
+/// parameter or as the `expires_...` path option, is covered by the signature and enforced.
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>
+}
+
+impl Signer {
+    pub fn new(key: &[u8]) -> Signer {
+        Signer {
+            key: key.to_vec()
+        }
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        let mut mac = HmacSha256::new_varkey(&self.key).expect("HMAC accepts keys of any length");
+        mac.input(message.as_bytes());
+        base64::encode_config(&mac.result().code(), base64::URL_SAFE_NO_PAD)
+    }
+
+    fn verify(&self, message: &str, signature: &str) -> Result<(), SignatureError> {
+        let signature = base64::decode_config(signature.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+            .map_err(|_| SignatureError::Invalid)?;
+
+        let mut mac = HmacSha256::new_varkey(&self.key).expect("HMAC accepts keys of any length");
+        mac.input(message.as_bytes());
+        mac.verify(&signature).map_err(|_| SignatureError::Invalid)
+    }
+
+    /// Checks a signed query string and returns it without the `signature` parameter.
+    pub fn verify_query<'a>(&self, query: &'a str) -> Result<&'a str, SignatureError> {
+        let (message, signature) = match query.rfind("signature=") {
+            Some(0) => ("", &query["signature=".len()..]),
+            Some(index) if query[..index].ends_with('&') => {
+                (&query[..index - 1], &query[index + "signature=".len()..])
+            },
+            _ => return Err(SignatureError::Missing)
+        };
+
+        self.verify(message, signature)?;
+
+        let expires = form_urlencoded::parse(message.as_bytes())
+            .filter(|(key, _)| key == "expires")
+            .map(|(_, value)| value.into_owned())
+            .last();
+        check_expiry(expires.as_deref())?;
+
+        Ok(message)
+    }
+
+    /// Checks a signed `{signature}/{options}/{source}` path and returns `{options}/{source}`.
+    pub fn verify_path<'a>(&self, path: &'a str) -> Result<&'a str, SignatureError> {
+        let mut segments = path.splitn(2, '/');
+        let (signature, message) = match (segments.next(), segments.next()) {
+            (Some(signature), Some(message)) if !signature.is_empty() => (signature, message),
+            _ => return Err(SignatureError::Missing)
+        };
+
+        self.verify(message, signature)?;
+
+        let options = message.split('/').next().unwrap_or("");
+        let expires = options.split(',')
+            .rev()
+            .find_map(|option| option.strip_prefix("expires_"));
+        check_expiry(expires)?;
+
+        Ok(message)
+    }
+}
+
+fn check_expiry(expires: Option<&str>) -> Result<(), SignatureError> {
+    let expires = match expires {
+        Some(expires) => expires.parse::<u64>().map_err(|_| SignatureError::Invalid)?,
+        None => return Ok(())
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+
+    if now > expires {
+        return Err(SignatureError::Expired);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> Signer {
+        Signer::new(b"secret")
+    }
+
+    fn signed_query(message: &str) -> String {
+        format!("{}&signature={}", message, signer().sign(message))
+    }
+
+    fn signed_path(message: &str) -> String {
+        format!("{}/{}", signer().sign(message), message)
+    }
+
+    #[test]
+    fn query_with_valid_signature() {
+        let query = signed_query("url=http://example.com/a.png&width=100");
+        assert_eq!(signer().verify_query(&query).unwrap(), "url=http://example.com/a.png&width=100");
+    }
+
+    #[test]
+    fn query_with_tampered_options() {
+        let query = signed_query("url=http://example.com/a.png&width=100").replace("width=100", "width=200");
+        assert!(matches!(signer().verify_query(&query), Err(SignatureError::Invalid)));
+    }
+
+    #[test]
+    fn query_signed_with_another_key() {
+        let query = format!("url=x&signature={}", Signer::new(b"other").sign("url=x"));
+        assert!(matches!(signer().verify_query(&query), Err(SignatureError::Invalid)));
+    }
+
+    #[test]
+    fn query_without_signature() {
+        assert!(matches!(signer().verify_query("url=x&width=100"), Err(SignatureError::Missing)));
+        assert!(matches!(signer().verify_query("url=x&mysignature=abc"), Err(SignatureError::Missing)));
+    }
+
+    #[test]
+    fn query_with_signature_not_last() {
+        let signature = signer().sign("url=x");
+        let query = format!("url=x&signature={}&width=100", signature);
+        assert!(matches!(signer().verify_query(&query), Err(SignatureError::Invalid)));
+
+        let query = format!("signature={}&url=x", signature);
+        assert!(matches!(signer().verify_query(&query), Err(SignatureError::Invalid)));
+    }
+
+    #[test]
+    fn query_with_expiry() {
+        let query = signed_query("url=x&expires=4102444800");
+        assert!(signer().verify_query(&query).is_ok());
+
+        let query = signed_query("url=x&expires=1");
+        assert!(matches!(signer().verify_query(&query), Err(SignatureError::Expired)));
+
+        let query = signed_query("url=x&expires=1").replace("expires=1", "expires=4102444800");
+        assert!(matches!(signer().verify_query(&query), Err(SignatureError::Invalid)));
+    }
+
+    #[test]
+    fn path_with_valid_signature() {
+        let path = signed_path("w_100/http://example.com/a.png");
+        assert_eq!(signer().verify_path(&path).unwrap(), "w_100/http://example.com/a.png");
+    }
+
+    #[test]
+    fn path_with_tampered_options() {
+        let path = signed_path("w_100/http://example.com/a.png").replace("w_100", "w_200");
+        assert!(matches!(signer().verify_path(&path), Err(SignatureError::Invalid)));
+    }
+
+    #[test]
+    fn path_without_signature() {
+        assert!(matches!(signer().verify_path("w_100"), Err(SignatureError::Missing)));
+        assert!(matches!(signer().verify_path("/w_100/http://example.com/a.png"), Err(SignatureError::Missing)));
+        assert!(matches!(signer().verify_path("w_100/http://example.com/a.png"), Err(SignatureError::Invalid)));
+    }
+
+    #[test]
+    fn path_with_expiry() {
+        let path = signed_path("w_100,expires_4102444800/http://example.com/a.png");
+        assert!(signer().verify_path(&path).is_ok());
+
+        let path = signed_path("w_100,expires_1/http://example.com/a.png");
+        assert!(matches!(signer().verify_path(&path), Err(SignatureError::Expired)));
+    }
+}
@@ -0,0 +1,175 @@
+use image::{Rgba, RgbaImage};
+
+/// The direction the crop window slides in and the fixed range it covers across it.
+///
+/// `fit=cover` only ever cuts pixels off one side, so the window slides along that axis while
+/// the other axis keeps a centered range (which is the whole image in all but rounding cases).
+struct Axis {
+    horizontal: bool,
+    window: u32,
+    free: u32,
+    cross_start: u32,
+    cross_len: u32
+}
+
+impl Axis {
+    fn new(image: &RgbaImage, width: u32, height: u32) -> Axis {
+        let (image_width, image_height) = image.dimensions();
+        let width = width.min(image_width);
+        let height = height.min(image_height);
+        let free_x = image_width - width;
+        let free_y = image_height - height;
+
+        if free_x >= free_y {
+            Axis { horizontal: true, window: width, free: free_x, cross_start: free_y / 2, cross_len: height }
+        } else {
+            Axis { horizontal: false, window: height, free: free_y, cross_start: free_x / 2, cross_len: width }
+        }
+    }
+
+    /// Image coordinates of the `index`th pixel of `line`.
+    fn coords(&self, line: u32, index: u32) -> (u32, u32) {
+        if self.horizontal {
+            (line, self.cross_start + index)
+        } else {
+            (self.cross_start + index, line)
+        }
+    }
+
+    /// Top-left corner of the crop window starting at `start`.
+    fn offset(&self, start: u32) -> (u32, u32) {
+        if self.horizontal {
+            (start, self.cross_start)
+        } else {
+            (self.cross_start, start)
+        }
+    }
+
+    /// Picks the best scoring window start, preferring the one closest to the center on ties.
+    fn best_start(&self, scores: &[f64]) -> u32 {
+        let center = self.free / 2;
+        let mut best = center;
+        let mut best_score = scores[center as usize];
+
+        for (start, &score) in scores.iter().enumerate() {
+            let start = start as u32;
+            let closer = (start as i64 - center as i64).abs() < (best as i64 - center as i64).abs();
+            if score > best_score || (score == best_score && closer) {
+                best = start;
+                best_score = score;
+            }
+        }
+
+        best
+    }
+}
+
+fn luma(pixel: &Rgba<u8>) -> u8 {
+    let [r, g, b, _] = pixel.0;
+    ((299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b)) / 1000) as u8
+}
+
+fn entropy(histogram: &[u32; 256], total: u32) -> f64 {
+    let total = f64::from(total);
+    histogram.iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = f64::from(count) / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Adds (or removes) the luminance of every pixel of `line` to the histogram.
+fn count_line(histogram: &mut [u32; 256], image: &RgbaImage, axis: &Axis, line: u32, add: bool) {
+    for index in 0..axis.cross_len {
+        let (x, y) = axis.coords(line, index);
+        let bin = &mut histogram[luma(image.get_pixel(x, y)) as usize];
+        if add {
+            *bin += 1;
+        } else {
+            *bin -= 1;
+        }
+    }
+}
+
+/// Crop offset whose window has the highest luminance entropy, i.e. the most detail.
+pub fn entropy_offset(image: &RgbaImage, width: u32, height: u32) -> (u32, u32) {
+    let axis = Axis::new(image, width, height);
+    if axis.free == 0 {
+        return axis.offset(0);
+    }
+
+    let mut histogram = [0u32; 256];
+    for line in 0..axis.window {
+        count_line(&mut histogram, image, &axis, line, true);
+    }
+
+    let total = axis.window * axis.cross_len;
+    let mut scores = Vec::with_capacity(axis.free as usize + 1);
+    scores.push(entropy(&histogram, total));
+
+    // Slide the window one line at a time, updating the histogram instead of recounting it
+    for start in 1..=axis.free {
+        count_line(&mut histogram, image, &axis, start - 1, false);
+        count_line(&mut histogram, image, &axis, start + axis.window - 1, true);
+        scores.push(entropy(&histogram, total));
+    }
+
+    axis.offset(axis.best_start(&scores))
+}
+
+/// Rough skin tone test on RGB values, after Kovac et al.
+fn is_skin(r: u8, g: u8, b: u8) -> bool {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    r > 95 && g > 40 && b > 20 && max - min > 15 && r > g && r > b && r - g > 15
+}
+
+/// Saliency of a single pixel: edge strength, plus bonuses for saturated colours and skin tones.
+fn saliency(image: &RgbaImage, x: u32, y: u32) -> u32 {
+    let pixel = image.get_pixel(x, y);
+    let [r, g, b, _] = pixel.0;
+    let value = i32::from(luma(pixel));
+
+    let left = if x > 0 { i32::from(luma(image.get_pixel(x - 1, y))) } else { value };
+    let up = if y > 0 { i32::from(luma(image.get_pixel(x, y - 1))) } else { value };
+    let edge = ((value - left).abs() + (value - up).abs()) as u32;
+
+    let saturation = u32::from(r.max(g).max(b) - r.min(g).min(b));
+    let skin = if is_skin(r, g, b) { 128 } else { 0 };
+
+    edge + saturation / 4 + skin
+}
+
+/// Crop offset whose window collects the most saliency, favouring edges, saturated colours and skin.
+pub fn attention_offset(image: &RgbaImage, width: u32, height: u32) -> (u32, u32) {
+    let axis = Axis::new(image, width, height);
+    if axis.free == 0 {
+        return axis.offset(0);
+    }
+
+    let lines = axis.window + axis.free;
+    let line_scores: Vec<u64> = (0..lines)
+        .map(|line| {
+            (0..axis.cross_len)
+                .map(|index| {
+                    let (x, y) = axis.coords(line, index);
+                    u64::from(saliency(image, x, y))
+                })
+                .sum()
+        })
+        .collect();
+
+    let mut window_score: u64 = line_scores[..axis.window as usize].iter().sum();
+    let mut scores = Vec::with_capacity(axis.free as usize + 1);
+    scores.push(window_score as f64);
+
+    for start in 1..=axis.free {
+        window_score -= line_scores[start as usize - 1];
+        window_score += line_scores[(start + axis.window - 1) as usize];
+        scores.push(window_score as f64);
+    }
+
+    axis.offset(axis.best_start(&scores))
+}